@@ -0,0 +1,335 @@
+use crate::{
+    decoder::{decode_file, mix_down, summarize_samples, DecodeMode},
+    engine::{click_sound, EngineCommand, EngineState, LooperEngine, RESAMPLE_SAMPLE_RATE},
+    recorder::{ChannelMode, InputRouting, Recorder, RecordingFormat, DEFAULT_COUNT_IN_BPM},
+    render::{render_loop_to_wav, write_wav, RenderSettings, DEFAULT_RENDER_SAMPLE_RATE},
+};
+use clap::{Args, Parser, Subcommand};
+use cpal::traits::{DeviceTrait, HostTrait};
+use crossbeam::channel::{bounded, Receiver, RecvTimeoutError, Sender};
+use kittyaudio::{Device, Mixer, StreamSettings};
+use std::{
+    io::Write,
+    path::PathBuf,
+    thread,
+    time::{Duration, Instant},
+};
+
+#[derive(Parser)]
+#[command(name = "useless-looper", about = "A toy audio looper", version)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<CliCommand>,
+}
+
+#[derive(Subcommand)]
+pub enum CliCommand {
+    /// Loop a file on an output device without opening the window
+    Play {
+        input: PathBuf,
+        #[command(flatten)]
+        loop_args: LoopArgs,
+        /// Output device name, see `devices`
+        #[arg(long)]
+        device: Option<String>,
+        /// Stop after this many seconds instead of playing until interrupted
+        #[arg(long)]
+        duration: Option<f64>,
+        /// Tape what is played to this WAV file when playback stops
+        #[arg(long, requires = "duration")]
+        resample: Option<PathBuf>,
+    },
+    /// Bounce the looped file to a WAV without an audio device
+    Render {
+        input: PathBuf,
+        #[command(flatten)]
+        loop_args: LoopArgs,
+        #[arg(short, long)]
+        output: PathBuf,
+        #[arg(long, default_value_t = 10.0)]
+        seconds: f64,
+        #[arg(long, default_value_t = DEFAULT_RENDER_SAMPLE_RATE)]
+        sample_rate: u32,
+    },
+    /// Record the default input to a WAV file
+    Record {
+        #[arg(short, long)]
+        output: PathBuf,
+        #[arg(long, default_value_t = 5.0)]
+        seconds: f64,
+        /// First input channel to record, counting from 1
+        #[arg(long, default_value_t = 1)]
+        channel: usize,
+        /// Sum the input pair to mono instead of recording stereo
+        #[arg(long)]
+        mono: bool,
+        /// Sample format of the file: 16, 24 or 32f
+        #[arg(long, default_value_t = RecordingFormat::Int16)]
+        format: RecordingFormat,
+        /// Wait for the input to reach this level before recording
+        #[arg(long, conflicts_with = "count_in")]
+        threshold: Option<f32>,
+        /// Play this many metronome beats before recording
+        #[arg(long)]
+        count_in: Option<u32>,
+        /// Tempo of the count-in
+        #[arg(long, default_value_t = DEFAULT_COUNT_IN_BPM)]
+        bpm: f64,
+    },
+    /// Print duration and format of a file
+    Info {
+        input: PathBuf,
+        /// Report what could be read from a damaged file instead of failing
+        #[arg(long)]
+        partial: bool,
+    },
+    /// Draw the waveform of an audio file in the terminal
+    Waveform {
+        input: PathBuf,
+        #[arg(long, default_value_t = 80)]
+        width: usize,
+        #[arg(long, default_value_t = 12)]
+        height: usize,
+        /// Draw what could be read from a damaged file instead of failing
+        #[arg(long)]
+        partial: bool,
+    },
+    /// List output devices
+    Devices,
+}
+
+#[derive(Args)]
+pub struct LoopArgs {
+    /// Loop start in seconds
+    #[arg(long, default_value_t = 0.0)]
+    start: f64,
+    /// Loop length in seconds, the whole file by default
+    #[arg(long)]
+    length: Option<f64>,
+    /// Playback rate factor, 1.0 is the original pitch
+    #[arg(long, default_value_t = 1.0)]
+    pitch: f64,
+}
+
+pub fn run(command: CliCommand) -> anyhow::Result<()> {
+    match command {
+        CliCommand::Play {
+            input,
+            loop_args,
+            device,
+            duration,
+            resample,
+        } => play(input, loop_args, device, duration, resample),
+        CliCommand::Render {
+            input,
+            loop_args,
+            output,
+            seconds,
+            sample_rate,
+        } => {
+            let audio = decode_file(&input, DecodeMode::Strict)?;
+            let sound_duration = audio.duration();
+            let settings = RenderSettings {
+                loop_start: loop_args.start,
+                loop_length: loop_args.length.unwrap_or(sound_duration),
+                pitch: loop_args.pitch,
+                seconds,
+                sample_rate,
+            };
+            render_loop_to_wav(audio.to_sound(), &output, settings)?;
+            println!("Rendered {}s to {}", seconds, output.display());
+            Ok(())
+        }
+        CliCommand::Record {
+            output,
+            seconds,
+            channel,
+            mono,
+            format,
+            threshold,
+            count_in,
+            bpm,
+        } => {
+            let mut recorder = Recorder::default();
+            recorder.set_routing(InputRouting {
+                first_channel: channel.saturating_sub(1),
+                mode: if mono {
+                    ChannelMode::Downmix
+                } else {
+                    ChannelMode::Stereo
+                },
+            })?;
+            recorder.output_path = Some(output.clone());
+            recorder.format = format;
+            recorder.open()?;
+            if let Some(beats) = count_in {
+                count_in_beats(beats, bpm);
+            }
+            match threshold {
+                Some(threshold) => {
+                    recorder.arm(threshold)?;
+                    println!("Waiting for input above {}", threshold);
+                    while !recorder.is_recording() {
+                        thread::sleep(Duration::from_millis(5));
+                    }
+                }
+                None => recorder.start()?,
+            }
+            thread::sleep(Duration::from_secs_f64(seconds));
+            recorder.stop();
+            println!("Recorded {}s to {}", seconds, output.display());
+            if recorder.dropped_samples() > 0 {
+                println!(
+                    "Dropped {} samples in {} overruns",
+                    recorder.dropped_samples(),
+                    recorder.overruns()
+                );
+            }
+            if recorder.clipped_samples() > 0 {
+                println!("Input clipped on {} samples", recorder.clipped_samples());
+            }
+            Ok(())
+        }
+        CliCommand::Info { input, partial } => {
+            let audio = decode_file(&input, decode_mode(partial))?;
+            println!("File:        {}", input.display());
+            println!("Duration:    {:.3}s", audio.duration());
+            println!("Sample rate: {} Hz", audio.sample_rate);
+            println!("Channels:    {}", audio.channels);
+            println!("Frames:      {}", audio.frame_count());
+            if let Some(e) = audio.error {
+                println!("Decoded partially: {:#}", e);
+            }
+            Ok(())
+        }
+        CliCommand::Waveform {
+            input,
+            width,
+            height,
+            partial,
+        } => waveform(input, width, height, decode_mode(partial)),
+        CliCommand::Devices => {
+            let host = cpal::default_host();
+            for device in host.output_devices()? {
+                println!("{}", device.name()?);
+            }
+            Ok(())
+        }
+    }
+}
+
+fn play(
+    input: PathBuf,
+    loop_args: LoopArgs,
+    device: Option<String>,
+    duration: Option<f64>,
+    resample: Option<PathBuf>,
+) -> anyhow::Result<()> {
+    let audio = decode_file(&input, DecodeMode::Strict)?;
+    let sound_duration = audio.duration();
+    let sound = audio.to_sound();
+
+    let (engine_sender, engine_receiver): (Sender<EngineCommand>, Receiver<EngineCommand>) =
+        bounded(100);
+    let (state_sender, state_receiver): (Sender<EngineState>, Receiver<EngineState>) = bounded(8);
+
+    thread::spawn(move || {
+        let mut mixer = Mixer::new();
+        match device {
+            Some(name) => mixer.init_ex(Device::Name(name), StreamSettings::default()),
+            None => mixer.init(),
+        }
+        LooperEngine::new(mixer, 1).run(engine_receiver, state_sender);
+    });
+
+    engine_sender.send(EngineCommand::LoadSound { track: 0, sound })?;
+    engine_sender.send(EngineCommand::SetLoopSeconds {
+        track: 0,
+        start: loop_args.start,
+        length: loop_args.length.unwrap_or(sound_duration),
+    })?;
+    engine_sender.send(EngineCommand::SetPitch {
+        track: 0,
+        factor: loop_args.pitch,
+    })?;
+    if resample.is_some() {
+        engine_sender.send(EngineCommand::StartResample)?;
+    }
+
+    let started = Instant::now();
+    loop {
+        if let Some(limit) = duration {
+            if started.elapsed().as_secs_f64() >= limit {
+                break;
+            }
+        }
+        match state_receiver.recv_timeout(Duration::from_millis(100)) {
+            Ok(EngineState { tracks }) => {
+                let Some(state) = tracks.first() else {
+                    continue;
+                };
+                print!(
+                    "\r{:>8.3}s  loop {:.3}s..{:.3}s  rate {:.2}",
+                    state.playhead, state.loop_start, state.loop_end, state.playback_rate
+                );
+                std::io::stdout().flush()?;
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+    if let Some(path) = resample {
+        let (reply, frames_receiver) = bounded(1);
+        engine_sender.send(EngineCommand::StopResample { reply })?;
+        write_wav(&path, &frames_receiver.recv()?, RESAMPLE_SAMPLE_RATE)?;
+        print!("\nResampled to {}", path.display());
+    }
+    engine_sender.send(EngineCommand::Stop)?;
+    println!();
+    Ok(())
+}
+
+fn count_in_beats(beats: u32, bpm: f64) {
+    let mut mixer = Mixer::new();
+    mixer.init();
+    let beat = Duration::from_secs_f64(60.0 / bpm);
+    for beat_index in 0..beats {
+        mixer.play(click_sound(beat_index == 0));
+        println!("{}", beats - beat_index);
+        thread::sleep(beat);
+    }
+}
+
+fn decode_mode(partial: bool) -> DecodeMode {
+    match partial {
+        true => DecodeMode::Partial,
+        false => DecodeMode::Strict,
+    }
+}
+
+fn waveform(input: PathBuf, width: usize, height: usize, mode: DecodeMode) -> anyhow::Result<()> {
+    let audio = decode_file(&input, mode)?;
+    if let Some(e) = &audio.error {
+        eprintln!("Decoded partially: {:#}", e);
+    }
+    let bins = summarize_samples(&mix_down(&audio.samples, audio.channels), width);
+
+    // Rows run from full scale at the top to negative full scale at the bottom.
+    for row in 0..height {
+        let level = 1.0 - (row as f32 + 0.5) * 2.0 / height as f32;
+        let line: String = bins
+            .iter()
+            .map(|bin| {
+                if level.abs() <= bin.rms {
+                    '█'
+                } else if level >= bin.min && level <= bin.max {
+                    '░'
+                } else {
+                    ' '
+                }
+            })
+            .collect();
+        println!("{}", line);
+    }
+    Ok(())
+}
@@ -1,50 +1,440 @@
-use hound::{self, WavReader};
-use std::path::Path;
+use crate::utils::interleaved_to_frames;
+use anyhow::anyhow;
+use kittyaudio::{Frame, Sound};
+use std::{
+    fs::File,
+    io::{Cursor, ErrorKind},
+    path::Path,
+    sync::atomic::{AtomicBool, AtomicU32, Ordering},
+};
+use symphonia::core::{
+    audio::SampleBuffer,
+    codecs::{DecoderOptions, CODEC_TYPE_NULL},
+    errors::Error as SymphoniaError,
+    formats::FormatOptions,
+    io::{MediaSource, MediaSourceStream},
+    meta::MetadataOptions,
+    probe::Hint,
+};
 
-pub enum DecodedSamples {
-    F32(Vec<f32>),
-    I16(Vec<i16>),
+// Samples behind each bin of the finest pyramid level.
+const PEAK_PYRAMID_BASE: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DecodeMode {
+    // Any error fails the whole decode.
+    Strict,
+    // Keeps whatever was read before an error, skipping damaged packets.
+    Partial,
+}
+
+// Interleaved samples of a whole file, in -1.0..1.0 whatever the source format.
+pub struct DecodedAudio {
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub samples: Vec<f32>,
+    // What cut a partial decode short or made it skip data.
+    pub error: Option<anyhow::Error>,
+}
+
+impl DecodedAudio {
+    pub fn duration(&self) -> f64 {
+        self.frame_count() as f64 / self.sample_rate as f64
+    }
+
+    pub fn frame_count(&self) -> usize {
+        self.samples.len() / self.channels.max(1) as usize
+    }
+
+    pub fn frames(&self) -> Vec<Frame> {
+        interleaved_to_frames(&self.samples, self.channels)
+    }
+
+    pub fn to_sound(&self) -> Sound {
+        Sound::from_frames(self.sample_rate, &self.frames())
+    }
 }
 
-pub fn decode_wav<P: AsRef<Path>>(path: P) -> Result<DecodedSamples, hound::Error> {
-    let mut reader = WavReader::open(path)?;
-    let spec = reader.spec();
+// Shared between a decode running in the background and whoever is waiting on it.
+#[derive(Debug, Default)]
+pub struct DecodeProgress {
+    // Bits of the f32 fraction of the file decoded so far.
+    fraction: AtomicU32,
+    cancelled: AtomicBool,
+}
+
+impl DecodeProgress {
+    pub fn fraction(&self) -> f32 {
+        f32::from_bits(self.fraction.load(Ordering::Relaxed))
+    }
+
+    fn set_fraction(&self, fraction: f32) {
+        self.fraction
+            .store(fraction.clamp(0.0, 1.0).to_bits(), Ordering::Relaxed);
+    }
 
-    match spec.sample_format {
-        hound::SampleFormat::Float => {
-            // Handle 32-bit floating-point samples
-            let samples: Vec<f32> = reader.samples::<f32>().map(|s| s.unwrap()).collect();
-            Ok(DecodedSamples::F32(samples))
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
+
+pub fn decode_file<P: AsRef<Path>>(path: P, mode: DecodeMode) -> anyhow::Result<DecodedAudio> {
+    decode_file_with_progress(path, mode, &DecodeProgress::default())
+}
+
+// Decodes anything symphonia can probe, so playback and waveforms read files the same way.
+// Reports how far it got through `progress` and gives up once that is cancelled.
+pub fn decode_file_with_progress<P: AsRef<Path>>(
+    path: P,
+    mode: DecodeMode,
+    progress: &DecodeProgress,
+) -> anyhow::Result<DecodedAudio> {
+    let path = path.as_ref();
+    let extension = path.extension().and_then(|extension| extension.to_str());
+    decode_source(
+        Box::new(File::open(path)?),
+        extension,
+        &path.display().to_string(),
+        mode,
+        progress,
+    )
+}
+
+// Decodes audio bundled into the binary the same way files are.
+pub fn decode_bytes(
+    bytes: &'static [u8],
+    extension: &str,
+    mode: DecodeMode,
+) -> anyhow::Result<DecodedAudio> {
+    decode_source(
+        Box::new(Cursor::new(bytes)),
+        Some(extension),
+        "embedded audio",
+        mode,
+        &DecodeProgress::default(),
+    )
+}
+
+fn decode_source(
+    source: Box<dyn MediaSource>,
+    extension: Option<&str>,
+    name: &str,
+    mode: DecodeMode,
+    progress: &DecodeProgress,
+) -> anyhow::Result<DecodedAudio> {
+    let source = MediaSourceStream::new(source, Default::default());
+    let mut hint = Hint::new();
+    if let Some(extension) = extension {
+        hint.with_extension(extension);
+    }
+    let probed = symphonia::default::get_probe().format(
+        &hint,
+        source,
+        &FormatOptions::default(),
+        &MetadataOptions::default(),
+    )?;
+    let mut format = probed.format;
+    let track = format
+        .tracks()
+        .iter()
+        .find(|track| track.codec_params.codec != CODEC_TYPE_NULL)
+        .ok_or_else(|| anyhow!("No audio track in {}", name))?;
+    let track_id = track.id;
+    let total_frames = track.codec_params.n_frames;
+    let mut sample_rate = track.codec_params.sample_rate.unwrap_or_default();
+    let mut channels = track
+        .codec_params
+        .channels
+        .map(|channels| channels.count() as u16)
+        .unwrap_or_default();
+    let mut decoder =
+        symphonia::default::get_codecs().make(&track.codec_params, &DecoderOptions::default())?;
+
+    let mut samples = Vec::new();
+    let mut sample_buffer: Option<SampleBuffer<f32>> = None;
+    let mut error = None;
+    loop {
+        let packet = match format.next_packet() {
+            Ok(packet) => packet,
+            // Symphonia reports the end of the stream as an unexpected EOF.
+            Err(SymphoniaError::IoError(e)) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => {
+                error = Some(e);
+                break;
+            }
+        };
+        if progress.is_cancelled() {
+            return Err(anyhow!("Decoding {} was cancelled", name));
         }
-        hound::SampleFormat::Int => {
-            // Handle 16-bit integer samples
-            let samples: Vec<i16> = reader.samples::<i16>().map(|s| s.unwrap()).collect();
-            Ok(DecodedSamples::I16(samples))
+        if packet.track_id() != track_id {
+            continue;
         }
+        if let Some(total_frames) = total_frames.filter(|&frames| frames > 0) {
+            progress.set_fraction((packet.ts() + packet.dur()) as f32 / total_frames as f32);
+        }
+        let decoded = match decoder.decode(&packet) {
+            Ok(decoded) => decoded,
+            // In partial mode a damaged packet only costs its own samples.
+            Err(e @ SymphoniaError::DecodeError(_)) if mode == DecodeMode::Partial => {
+                error.get_or_insert(e);
+                continue;
+            }
+            Err(e) => {
+                error = Some(e);
+                break;
+            }
+        };
+        let spec = *decoded.spec();
+        sample_rate = spec.rate;
+        channels = spec.channels.count() as u16;
+        // The buffer counts interleaved samples, the decoded audio frames per channel.
+        let needed = decoded.capacity() * spec.channels.count();
+        if sample_buffer
+            .as_ref()
+            .is_none_or(|buffer| buffer.capacity() < needed)
+        {
+            sample_buffer = Some(SampleBuffer::new(decoded.capacity() as u64, spec));
+        }
+        if let Some(buffer) = sample_buffer.as_mut() {
+            buffer.copy_interleaved_ref(decoded);
+            samples.extend_from_slice(buffer.samples());
+        }
+    }
+
+    progress.set_fraction(1.0);
+    let mut error = error.map(|e| anyhow!(e).context(format!("Failed decoding {}", name)));
+    // A truncated file also ends in an unexpected EOF, only earlier than its header promised.
+    let decoded_frames = (samples.len() / channels.max(1) as usize) as u64;
+    if let Some(total_frames) = total_frames.filter(|&frames| decoded_frames < frames) {
+        error.get_or_insert_with(|| {
+            anyhow!(
+                "Only decoded {} of {} frames of {}",
+                decoded_frames,
+                total_frames,
+                name
+            )
+        });
+    }
+    match error {
+        Some(e) if mode == DecodeMode::Strict || samples.is_empty() => Err(e),
+        error => Ok(DecodedAudio {
+            sample_rate,
+            channels,
+            samples,
+            error,
+        }),
     }
 }
 
-pub fn compress_samples<T>(samples: &[T], target_length: usize) -> Vec<f32>
-where
-    T: Into<f32> + Copy,
-{
-    let total_samples = samples.len();
-    let samples_per_bin = total_samples as f64 / target_length as f64;
-    let mut compressed_samples = Vec::with_capacity(target_length);
+// Splits interleaved samples into one buffer per channel.
+pub fn deinterleave(samples: &[f32], channels: u16) -> Vec<Vec<f32>> {
+    let channels = channels.max(1) as usize;
+    (0..channels)
+        .map(|channel| {
+            samples
+                .iter()
+                .skip(channel)
+                .step_by(channels)
+                .copied()
+                .collect()
+        })
+        .collect()
+}
 
-    for bin in 0..target_length {
-        let start = (bin as f64 * samples_per_bin) as usize;
-        let end = ((bin as f64 + 1.0) * samples_per_bin).ceil() as usize;
-        let slice = &samples[start..end];
+// Averages interleaved channels into one.
+pub fn mix_down(samples: &[f32], channels: u16) -> Vec<f32> {
+    let channels = channels.max(1) as usize;
+    samples
+        .chunks_exact(channels)
+        .map(|frame| frame.iter().sum::<f32>() / channels as f32)
+        .collect()
+}
 
-        let sum: f32 = slice.iter().map(|&sample| sample.into()).sum();
-        let count = slice.len();
+// Extremes and loudness of a stretch of samples.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PeakBin {
+    pub min: f32,
+    pub max: f32,
+    pub rms: f32,
+}
 
-        if count > 0 {
-            compressed_samples.push(sum / count as f32);
-        } else {
-            compressed_samples.push(0.0);
+impl PeakBin {
+    pub fn from_samples(samples: &[f32]) -> Self {
+        if samples.is_empty() {
+            return Self::default();
         }
+        let (min, max) = samples
+            .iter()
+            .fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), &sample| {
+                (min.min(sample), max.max(sample))
+            });
+        let square_sum: f32 = samples.iter().map(|sample| sample * sample).sum();
+        Self {
+            min,
+            max,
+            rms: (square_sum / samples.len() as f32).sqrt(),
+        }
+    }
+
+    // One bin covering all of `bins`, which are taken to span equal stretches.
+    pub fn merge(bins: &[PeakBin]) -> Self {
+        if bins.is_empty() {
+            return Self::default();
+        }
+        let (min, max) = bins
+            .iter()
+            .fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), bin| {
+                (min.min(bin.min), max.max(bin.max))
+            });
+        let square_sum: f32 = bins.iter().map(|bin| bin.rms * bin.rms).sum();
+        Self {
+            min,
+            max,
+            rms: (square_sum / bins.len() as f32).sqrt(),
+        }
+    }
+}
+
+// Peak bins of one channel at halving resolutions, so any stretch of it can be drawn
+// at any width from about as many bins as there are pixels.
+#[derive(Debug, Clone, Default)]
+pub struct PeakPyramid {
+    sample_count: usize,
+    // Finest first, each level merging pairs of bins from the one before.
+    levels: Vec<Vec<PeakBin>>,
+}
+
+impl PeakPyramid {
+    pub fn new(samples: &[f32]) -> Self {
+        let mut levels = vec![samples
+            .chunks(PEAK_PYRAMID_BASE)
+            .map(PeakBin::from_samples)
+            .collect::<Vec<_>>()];
+        while let Some(level) = levels.last().filter(|level| level.len() > 1) {
+            let coarser = level.chunks(2).map(PeakBin::merge).collect();
+            levels.push(coarser);
+        }
+        Self {
+            sample_count: samples.len(),
+            levels,
+        }
+    }
+
+    pub fn from_levels(sample_count: usize, levels: Vec<Vec<PeakBin>>) -> Self {
+        Self {
+            sample_count,
+            levels,
+        }
+    }
+
+    pub fn sample_count(&self) -> usize {
+        self.sample_count
+    }
+
+    pub fn levels(&self) -> &[Vec<PeakBin>] {
+        &self.levels
+    }
+
+    // `width` bins for the stretch between `start` and `end`, given as fractions of the whole,
+    // read from the coarsest level that still has a bin for every one asked for.
+    pub fn bins(&self, start: f64, end: f64, width: usize) -> Vec<PeakBin> {
+        if self.sample_count == 0 || width == 0 {
+            return Vec::new();
+        }
+        let start = start.clamp(0.0, 1.0);
+        let end = end.clamp(start, 1.0);
+        let visible_samples = (end - start) * self.sample_count as f64;
+        let level_index = self
+            .levels
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(level_index, _)| {
+                visible_samples / (PEAK_PYRAMID_BASE << level_index) as f64 >= width as f64
+            })
+            .map_or(0, |(level_index, _)| level_index);
+        let level = &self.levels[level_index];
+        let first = start * level.len() as f64;
+        let bins_per_width = (end - start) * level.len() as f64 / width as f64;
+        (0..width)
+            .map(|bin| {
+                let from = ((first + bin as f64 * bins_per_width) as usize).min(level.len() - 1);
+                let to = ((first + (bin as f64 + 1.0) * bins_per_width).ceil() as usize)
+                    .clamp(from + 1, level.len());
+                PeakBin::merge(&level[from..to])
+            })
+            .collect()
+    }
+}
+
+// Splits samples into `bins` equal stretches and keeps the peaks and RMS of each.
+pub fn summarize_samples(samples: &[f32], bins: usize) -> Vec<PeakBin> {
+    let samples_per_bin = samples.len() as f64 / bins as f64;
+    (0..bins)
+        .map(|bin| {
+            let start = ((bin as f64 * samples_per_bin) as usize).min(samples.len());
+            let end = (((bin as f64 + 1.0) * samples_per_bin).ceil() as usize)
+                .clamp(start, samples.len());
+            PeakBin::from_samples(&samples[start..end])
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Sixteen base bins of a rising ramp, so each bin's peaks tell which samples it covers.
+    fn ramp_pyramid() -> PeakPyramid {
+        let samples: Vec<f32> = (0..PEAK_PYRAMID_BASE * 16).map(|i| i as f32).collect();
+        PeakPyramid::new(&samples)
+    }
+
+    fn peaks(bins: &[PeakBin]) -> Vec<(f32, f32)> {
+        bins.iter().map(|bin| (bin.min, bin.max)).collect()
+    }
+
+    #[test]
+    fn halves_down_to_one_bin() {
+        let pyramid = ramp_pyramid();
+        let lengths: Vec<usize> = pyramid.levels().iter().map(Vec::len).collect();
+        assert_eq!(lengths, [16, 8, 4, 2, 1]);
+        assert_eq!(pyramid.levels()[4][0].max, 4095.0);
+    }
+
+    #[test]
+    fn reads_the_coarsest_level_with_a_bin_per_pixel() {
+        let pyramid = ramp_pyramid();
+        // Three pixels are served by the four-bin level, the first taking two of its bins.
+        let bins = pyramid.bins(0.0, 1.0, 3);
+        assert_eq!(bins[0].min, 0.0);
+        assert_eq!(bins[0].max, 2047.0);
+        // More pixels than base bins repeat the base bins.
+        let bins = pyramid.bins(0.0, 1.0, 64);
+        assert_eq!(bins.len(), 64);
+        assert_eq!(peaks(&bins[..4]), [(0.0, 255.0); 4]);
+        assert_eq!(peaks(&bins[60..]), [(3840.0, 4095.0); 4]);
+    }
+
+    #[test]
+    fn clamps_the_stretch_to_the_sound() {
+        let pyramid = ramp_pyramid();
+        assert_eq!(
+            peaks(&pyramid.bins(-1.0, 2.0, 16)),
+            peaks(&pyramid.bins(0.0, 1.0, 16))
+        );
+        // An end before the start shows the one bin at the start.
+        assert_eq!(peaks(&pyramid.bins(0.75, 0.5, 4)), [(3072.0, 3327.0); 4]);
+        assert_eq!(peaks(&pyramid.bins(1.0, 1.0, 2)), [(3840.0, 4095.0); 2]);
+    }
+
+    #[test]
+    fn nothing_to_show_gives_no_bins() {
+        assert!(ramp_pyramid().bins(0.0, 1.0, 0).is_empty());
+        assert!(PeakPyramid::new(&[]).bins(0.0, 1.0, 16).is_empty());
     }
-    compressed_samples
 }
@@ -0,0 +1,732 @@
+use crate::utils::resample_frames;
+use crossbeam::channel::{Receiver, RecvTimeoutError, Sender};
+use kittyaudio::{Change, Command, Easing, Frame, Mixer, PlaybackRate, Sound, SoundHandle};
+use std::{
+    ops::Range,
+    time::{Duration, Instant},
+};
+
+pub const SMALLEST_LOOP_UPPER_BOUND: f64 = 0.008;
+const COMMAND_DURATION: f64 = 0.1;
+const STATE_PUBLISH_INTERVAL: Duration = Duration::from_millis(16);
+const OVERDUB_COMMIT_INTERVAL: Duration = Duration::from_millis(100);
+pub const RESAMPLE_SAMPLE_RATE: u32 = 44100;
+const CLICK_SAMPLE_RATE: u32 = 44100;
+const CLICK_SECONDS: f64 = 0.03;
+const CLICK_FREQUENCY: f64 = 1000.0;
+const CLICK_ACCENT_FREQUENCY: f64 = 1500.0;
+
+pub enum EngineCommand {
+    LoadSound {
+        track: usize,
+        sound: Sound,
+    },
+    SetLoopSeconds {
+        track: usize,
+        start: f64,
+        length: f64,
+    },
+    SetPitch {
+        track: usize,
+        factor: f64,
+    },
+    // Starts a new overdub pass, so its first input is lined up with the playhead again.
+    StartOverdub {
+        track: usize,
+    },
+    // Input that just finished arriving, summed into the loop right after the previous input.
+    Overdub {
+        track: usize,
+        frames: Vec<Frame>,
+        sample_rate: u32,
+    },
+    SetOverdubFeedback {
+        track: usize,
+        feedback: f32,
+    },
+    // Punch region in seconds, clamped to the loop when recording.
+    SetPunch {
+        track: usize,
+        start: f64,
+        end: f64,
+    },
+    // Replaces the punch region with the incoming `Punch` input on the next pass through it.
+    ArmPunch {
+        track: usize,
+    },
+    CancelPunch {
+        track: usize,
+    },
+    Punch {
+        track: usize,
+        frames: Vec<Frame>,
+        sample_rate: u32,
+    },
+    SelectTake {
+        track: usize,
+        take: usize,
+    },
+    // Starts taping what the tracks play, as they are played.
+    StartResample,
+    // Stops taping and hands the taped frames, at `RESAMPLE_SAMPLE_RATE`, back through `reply`.
+    StopResample {
+        reply: Sender<Vec<Frame>>,
+    },
+    // A metronome tick, accented on the first beat of a bar.
+    Click {
+        accent: bool,
+    },
+    Pause,
+    Resume,
+    Stop,
+}
+
+// Snapshot of what the engine has actually applied to one track.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TrackState {
+    pub playhead: f64,
+    pub loop_start: f64,
+    pub loop_end: f64,
+    pub playback_rate: f64,
+    pub sound_duration: f64,
+    pub sample_rate: u32,
+    pub is_playing: bool,
+    pub overdub_feedback: f32,
+    pub punch_state: PunchState,
+    pub take_count: usize,
+    pub active_take: usize,
+    // Punches finished since the engine started, counting up so the UI can tell one has ended.
+    pub punch_count: usize,
+}
+
+impl TrackState {
+    // Loop start and end as whole frames of the loaded sound. The end never passes the sound,
+    // since `loop_bounds` keeps it inside, and overdubs truncate to frames the same way.
+    pub fn loop_frames(&self) -> (u64, u64) {
+        let sample_rate = self.sample_rate as f64;
+        (
+            (self.loop_start * sample_rate) as u64,
+            (self.loop_end * sample_rate) as u64,
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum PunchState {
+    #[default]
+    Off,
+    // Armed, waiting for the playhead to reach the punch-in point.
+    Waiting,
+    Recording,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct EngineState {
+    pub tracks: Vec<TrackState>,
+}
+
+// Clamps a requested loop to what the engine can actually play, or None if it starts past the end.
+pub fn loop_bounds(start: f64, length: f64, sound_duration: f64) -> Option<(f64, f64)> {
+    let lower_bound = start.max(0.0);
+    if lower_bound >= sound_duration {
+        return None;
+    }
+    let upper_bound = (lower_bound + length)
+        .max(lower_bound + SMALLEST_LOOP_UPPER_BOUND)
+        .min(sound_duration);
+    Some((lower_bound, upper_bound))
+}
+
+// Short decaying sine, loud enough to count along with but out of the way of the loops.
+pub fn click_sound(accent: bool) -> Sound {
+    let frequency = match accent {
+        true => CLICK_ACCENT_FREQUENCY,
+        false => CLICK_FREQUENCY,
+    };
+    let length = (CLICK_SECONDS * CLICK_SAMPLE_RATE as f64) as usize;
+    let frames: Vec<Frame> = (0..length)
+        .map(|i| {
+            let t = i as f64 / CLICK_SAMPLE_RATE as f64;
+            let envelope = (-t / CLICK_SECONDS * 5.0).exp();
+            let sample = ((std::f64::consts::TAU * frequency * t).sin() * envelope * 0.5) as f32;
+            Frame {
+                left: sample,
+                right: sample,
+            }
+        })
+        .collect();
+    Sound::from_frames(CLICK_SAMPLE_RATE, &frames)
+}
+
+struct Track {
+    sound: Option<SoundHandle>,
+    frames: Vec<Frame>,
+    sample_rate: u32,
+    sound_duration: f64,
+    loop_start: f64,
+    loop_end: f64,
+    playback_rate: f64,
+    is_playing: bool,
+    overdub_feedback: f32,
+    // The frames changed and the playing sound has to be rebuilt from them.
+    rebuild_pending: bool,
+    punch_start: f64,
+    punch_end: f64,
+    punch_state: PunchState,
+    // Loop frame the last punch input landed on.
+    punch_last_position: Option<usize>,
+    // Offset into the loop the next overdub or punch input is written at. Set from the playhead
+    // by the first input, then moved on by each input's length so delivery jitter can't
+    // make inputs overlap or leave gaps.
+    write_cursor: Option<usize>,
+    // Whole-sound versions left by each punch, the first one being what was there before.
+    takes: Vec<Vec<Frame>>,
+    active_take: usize,
+    punch_count: usize,
+    // Unplayed copy of the sound, advanced by hand while resampling to reproduce the mixer output.
+    mirror: Option<Sound>,
+}
+
+impl Track {
+    fn new() -> Self {
+        Self {
+            sound: None,
+            frames: Vec::new(),
+            sample_rate: 0,
+            sound_duration: 0.0,
+            loop_start: 0.0,
+            loop_end: 0.0,
+            playback_rate: 1.0,
+            is_playing: false,
+            overdub_feedback: 1.0,
+            rebuild_pending: false,
+            punch_start: 0.0,
+            punch_end: 0.0,
+            punch_state: PunchState::Off,
+            punch_last_position: None,
+            write_cursor: None,
+            takes: Vec::new(),
+            active_take: 0,
+            punch_count: 0,
+            mirror: None,
+        }
+    }
+
+    fn state(&self) -> TrackState {
+        TrackState {
+            playhead: self
+                .sound
+                .as_ref()
+                .map(|sound| sound.position())
+                .unwrap_or(0.0),
+            loop_start: self.loop_start,
+            loop_end: self.loop_end,
+            playback_rate: self.playback_rate,
+            sound_duration: self.sound_duration,
+            sample_rate: self.sample_rate,
+            is_playing: self.is_playing,
+            overdub_feedback: self.overdub_feedback,
+            punch_state: self.punch_state,
+            take_count: self.takes.len(),
+            active_take: self.active_take,
+            punch_count: self.punch_count,
+        }
+    }
+
+    fn load_sound(&mut self, mixer: &mut Mixer, new_sound: Sound) {
+        if let Some(sound) = &self.sound {
+            release(sound, self.sound_duration);
+        }
+        self.frames = new_sound.frames().to_vec();
+        self.sample_rate = new_sound.sample_rate();
+        self.rebuild_pending = false;
+        self.punch_state = PunchState::Off;
+        self.write_cursor = None;
+        self.takes.clear();
+        self.active_take = 0;
+        self.sound_duration = new_sound.duration().as_secs_f64();
+        self.loop_start = 0.0;
+        self.loop_end = self.sound_duration;
+        let sound = mixer.play(new_sound);
+        sound.set_loop_enabled(true);
+        self.sound = Some(sound);
+        self.is_playing = true;
+        self.mirror = None;
+    }
+
+    fn set_loop(&mut self, start: f64, length: f64) {
+        let Some(sound) = &self.sound else {
+            return;
+        };
+        let Some((lower_bound, upper_bound)) = loop_bounds(start, length, self.sound_duration)
+        else {
+            return;
+        };
+        self.loop_start = lower_bound;
+        self.loop_end = upper_bound;
+
+        let loop_commands = || {
+            [
+                Command::new(
+                    Change::Position(lower_bound),
+                    Easing::ExpoOut,
+                    0.0,
+                    COMMAND_DURATION,
+                ),
+                Command::new(
+                    Change::LoopSeconds(lower_bound..=upper_bound),
+                    Easing::ExpoOut,
+                    0.0,
+                    COMMAND_DURATION,
+                ),
+            ]
+        };
+        for command in loop_commands() {
+            sound.add_command(command);
+        }
+        // The mirror eases into the new loop the same way, so the tape has the scroll moves as heard.
+        if let Some(mirror) = self.mirror.as_mut() {
+            for command in loop_commands() {
+                mirror.add_command(command);
+            }
+        }
+    }
+
+    fn set_pitch(&mut self, factor: f64) {
+        let Some(sound) = &self.sound else {
+            return;
+        };
+        let pitch_command = || {
+            Command::new(
+                Change::PlaybackRate(PlaybackRate::Factor(factor)),
+                Easing::ExpoOut,
+                0.0,
+                COMMAND_DURATION,
+            )
+        };
+        sound.add_command(pitch_command());
+        self.playback_rate = factor;
+
+        if let Some(mirror) = self.mirror.as_mut() {
+            mirror.add_command(pitch_command());
+        }
+    }
+
+    // Frame range of the loop and the loop frames the input covers, following on from the last input.
+    fn input_positions(
+        &mut self,
+        input: &[Frame],
+        input_sample_rate: u32,
+    ) -> Option<(Range<usize>, Vec<Frame>, Vec<usize>)> {
+        let sound = self.sound.as_ref()?;
+        if self.frames.is_empty() || self.playback_rate <= 0.0 {
+            return None;
+        }
+        // Stretch the input to the number of source frames the playhead covered meanwhile.
+        let input = resample_frames(
+            input,
+            input_sample_rate as f64,
+            self.sample_rate as f64 * self.playback_rate,
+        );
+        let sample_rate = self.sample_rate as f64;
+        let loop_start = ((self.loop_start * sample_rate) as usize).min(self.frames.len() - 1);
+        let loop_end =
+            ((self.loop_end * sample_rate) as usize).clamp(loop_start + 1, self.frames.len());
+        let loop_length = loop_end - loop_start;
+        // Only the first input is lined up to end at the playhead.
+        let cursor = match self.write_cursor {
+            Some(cursor) => cursor % loop_length,
+            None => {
+                let playhead = (sound.position() * sample_rate) as isize;
+                (playhead - input.len() as isize - loop_start as isize)
+                    .rem_euclid(loop_length as isize) as usize
+            }
+        };
+        let positions = (0..input.len())
+            .map(|i| loop_start + (cursor + i) % loop_length)
+            .collect();
+        self.write_cursor = Some((cursor + input.len()) % loop_length);
+        Some((loop_start..loop_end, input, positions))
+    }
+
+    fn overdub(&mut self, input: &[Frame], input_sample_rate: u32) {
+        let Some((_, input, positions)) = self.input_positions(input, input_sample_rate) else {
+            return;
+        };
+        for (frame, position) in input.iter().zip(positions) {
+            let existing = &mut self.frames[position];
+            existing.left = existing.left * self.overdub_feedback + frame.left;
+            existing.right = existing.right * self.overdub_feedback + frame.right;
+        }
+        self.rebuild_pending = true;
+    }
+
+    fn arm_punch(&mut self) {
+        if self.frames.is_empty() {
+            return;
+        }
+        // The take being punched into remembers its frames, so a cancelled punch can go back.
+        match self.takes.get_mut(self.active_take) {
+            Some(take) => *take = self.frames.clone(),
+            None => {
+                self.takes.push(self.frames.clone());
+                self.active_take = 0;
+            }
+        }
+        self.punch_state = PunchState::Waiting;
+        self.punch_last_position = None;
+        self.write_cursor = None;
+    }
+
+    // Drops a half-done punch and goes back to the take it started from.
+    fn cancel_punch(&mut self) {
+        if self.punch_state == PunchState::Recording {
+            self.frames = self.takes[self.active_take].clone();
+            self.rebuild_pending = true;
+        }
+        self.punch_state = PunchState::Off;
+    }
+
+    fn punch(&mut self, input: &[Frame], input_sample_rate: u32) {
+        if self.punch_state == PunchState::Off {
+            return;
+        }
+        let Some((loop_range, input, positions)) = self.input_positions(input, input_sample_rate)
+        else {
+            return;
+        };
+        let sample_rate = self.sample_rate as f64;
+        let punch_start =
+            ((self.punch_start * sample_rate) as usize).clamp(loop_range.start, loop_range.end);
+        let punch_end =
+            ((self.punch_end * sample_rate) as usize).clamp(punch_start, loop_range.end);
+        let punch_range = punch_start..punch_end;
+
+        for (frame, position) in input.iter().zip(positions) {
+            let inside = punch_range.contains(&position);
+            // Arming mid-region waits for the next pass, as if it had been inside all along.
+            let was_inside = self
+                .punch_last_position
+                .is_none_or(|last| punch_range.contains(&last));
+            // The write cursor only goes backwards when it comes around the loop.
+            let wrapped = self.punch_last_position.is_some_and(|last| position < last);
+            self.punch_last_position = Some(position);
+
+            if self.punch_state == PunchState::Recording && (!inside || wrapped) {
+                self.takes.push(self.frames.clone());
+                self.active_take = self.takes.len() - 1;
+                self.punch_state = PunchState::Off;
+                self.punch_count += 1;
+                break;
+            }
+            if self.punch_state == PunchState::Waiting && inside && (!was_inside || wrapped) {
+                self.punch_state = PunchState::Recording;
+            }
+            if self.punch_state == PunchState::Recording {
+                self.frames[position] = *frame;
+                self.rebuild_pending = true;
+            }
+        }
+    }
+
+    // Ignored while a punch is armed or recording, which only ends through the UI.
+    fn select_take(&mut self, take: usize) {
+        if take >= self.takes.len()
+            || take == self.active_take
+            || self.punch_state != PunchState::Off
+        {
+            return;
+        }
+        // Keeps anything overdubbed onto the take being left.
+        self.takes[self.active_take] = std::mem::take(&mut self.frames);
+        self.frames = self.takes[take].clone();
+        self.active_take = take;
+        self.rebuild_pending = true;
+    }
+
+    // Swaps the playing sound for one built from the changed frames, keeping its position.
+    fn commit_frames(&mut self, mixer: &mut Mixer) {
+        if !self.rebuild_pending {
+            return;
+        }
+        self.rebuild_pending = false;
+        if self.sound.is_none() {
+            return;
+        }
+        // Copying the frames takes a while, so the position is only read once they are copied.
+        let mut new_sound = self.build_sound();
+        let mirror = self.mirror.is_some().then(|| self.build_sound());
+        let Some(old_sound) = &self.sound else {
+            return;
+        };
+        let position = old_sound.position();
+        new_sound.seek_to(position);
+        let sound = mixer.play(new_sound);
+        if !self.is_playing {
+            sound.pause();
+        }
+        release(old_sound, self.sound_duration);
+        self.sound = Some(sound);
+        // The mirror runs on its own clock, so it carries on from where it is rather than
+        // jumping to the device's playhead.
+        if let Some(mut mirror) = mirror {
+            if let Some(old_mirror) = &self.mirror {
+                mirror.seek_to(old_mirror.position());
+            }
+            self.mirror = Some(mirror);
+        }
+    }
+
+    // A sound from the current frames with the loop and pitch the track has applied.
+    fn build_sound(&self) -> Sound {
+        let mut sound = Sound::from_frames(self.sample_rate, &self.frames);
+        sound.set_loop_enabled(true);
+        sound.set_loop(self.loop_start..=self.loop_end);
+        sound.set_playback_rate(PlaybackRate::Factor(self.playback_rate));
+        sound
+    }
+
+    fn start_mirror(&mut self) {
+        self.mirror = self.sound.as_ref().map(|sound| {
+            let mut mirror = self.build_sound();
+            mirror.seek_to(sound.position());
+            mirror
+        });
+    }
+
+    fn next_mirror_frame(&mut self) -> Frame {
+        match self.mirror.as_mut() {
+            Some(mirror) if self.is_playing => mirror.next_frame(RESAMPLE_SAMPLE_RATE),
+            _ => Frame {
+                left: 0.0,
+                right: 0.0,
+            },
+        }
+    }
+
+    fn pause(&mut self) {
+        if let Some(sound) = &self.sound {
+            sound.pause();
+            self.is_playing = false;
+        }
+    }
+
+    fn resume(&mut self) {
+        if let Some(sound) = &self.sound {
+            sound.resume();
+            self.is_playing = true;
+        }
+    }
+
+    fn stop(&mut self) {
+        if let Some(sound) = &self.sound {
+            sound.pause();
+            sound.seek_to(self.loop_start);
+            self.is_playing = false;
+        }
+        if let Some(mirror) = self.mirror.as_mut() {
+            mirror.seek_to(self.loop_start);
+        }
+    }
+}
+
+// The mixer only drops sounds that have finished, and a looping one never does, so a sound
+// that is replaced stops looping and jumps to its end to be dropped on the next callback.
+fn release(sound: &SoundHandle, duration: f64) {
+    sound.set_loop_enabled(false);
+    sound.seek_to(duration);
+    sound.resume();
+}
+
+// Output taped so far, and when taping began, so the tape keeps pace with the clock.
+struct Resampler {
+    frames: Vec<Frame>,
+    started: Instant,
+    // Copies of the clicks played meanwhile, with the tape frames each has left.
+    clicks: Vec<(Sound, usize)>,
+}
+
+pub struct LooperEngine {
+    mixer: Mixer,
+    tracks: Vec<Track>,
+    resampler: Option<Resampler>,
+}
+
+impl LooperEngine {
+    pub fn new(mixer: Mixer, track_count: usize) -> Self {
+        Self {
+            mixer,
+            tracks: (0..track_count).map(|_| Track::new()).collect(),
+            resampler: None,
+        }
+    }
+
+    pub fn run(mut self, receiver: Receiver<EngineCommand>, state_sender: Sender<EngineState>) {
+        let mut last_published = Instant::now();
+        let mut last_committed = Instant::now();
+        loop {
+            match receiver.recv_timeout(STATE_PUBLISH_INTERVAL) {
+                Ok(command) => {
+                    // The tape is caught up first, so the command lands on it when it was heard.
+                    self.resample();
+                    self.handle(command);
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+            self.resample();
+            if last_committed.elapsed() >= OVERDUB_COMMIT_INTERVAL {
+                for track in self.tracks.iter_mut() {
+                    track.commit_frames(&mut self.mixer);
+                }
+                last_committed = Instant::now();
+            }
+            if last_published.elapsed() >= STATE_PUBLISH_INTERVAL {
+                // The UI only cares about the latest snapshot, so a full channel just drops this one.
+                let _s = state_sender.try_send(self.state());
+                last_published = Instant::now();
+            }
+        }
+    }
+
+    // Catches the tape up with the time that has passed since taping began.
+    fn resample(&mut self) {
+        let Some(resampler) = self.resampler.as_mut() else {
+            return;
+        };
+        let target =
+            (resampler.started.elapsed().as_secs_f64() * RESAMPLE_SAMPLE_RATE as f64) as usize;
+        while resampler.frames.len() < target {
+            let mut frame = Frame {
+                left: 0.0,
+                right: 0.0,
+            };
+            for track in self.tracks.iter_mut() {
+                let track_frame = track.next_mirror_frame();
+                frame.left += track_frame.left;
+                frame.right += track_frame.right;
+            }
+            for (click, frames_left) in resampler.clicks.iter_mut() {
+                let click_frame = click.next_frame(RESAMPLE_SAMPLE_RATE);
+                frame.left += click_frame.left;
+                frame.right += click_frame.right;
+                *frames_left -= 1;
+            }
+            resampler.clicks.retain(|(_, frames_left)| *frames_left > 0);
+            resampler.frames.push(frame);
+        }
+    }
+
+    pub fn state(&self) -> EngineState {
+        EngineState {
+            tracks: self.tracks.iter().map(Track::state).collect(),
+        }
+    }
+
+    pub fn handle(&mut self, command: EngineCommand) {
+        match command {
+            EngineCommand::LoadSound { track, sound } => {
+                if let Some(t) = self.tracks.get_mut(track) {
+                    t.load_sound(&mut self.mixer, sound);
+                    // Whatever comes in while taping is taped too, empty tracks included.
+                    if self.resampler.is_some() {
+                        t.start_mirror();
+                    }
+                }
+            }
+            EngineCommand::SetLoopSeconds {
+                track,
+                start,
+                length,
+            } => {
+                if let Some(t) = self.tracks.get_mut(track) {
+                    t.set_loop(start, length);
+                }
+            }
+            EngineCommand::SetPitch { track, factor } => {
+                if let Some(t) = self.tracks.get_mut(track) {
+                    t.set_pitch(factor);
+                }
+            }
+            EngineCommand::StartOverdub { track } => {
+                if let Some(t) = self.tracks.get_mut(track) {
+                    t.write_cursor = None;
+                }
+            }
+            EngineCommand::Overdub {
+                track,
+                frames,
+                sample_rate,
+            } => {
+                if let Some(t) = self.tracks.get_mut(track) {
+                    t.overdub(&frames, sample_rate);
+                }
+            }
+            EngineCommand::SetOverdubFeedback { track, feedback } => {
+                if let Some(t) = self.tracks.get_mut(track) {
+                    t.overdub_feedback = feedback.clamp(0.0, 1.0);
+                }
+            }
+            EngineCommand::SetPunch { track, start, end } => {
+                if let Some(t) = self.tracks.get_mut(track) {
+                    t.punch_start = start.min(end);
+                    t.punch_end = start.max(end);
+                }
+            }
+            EngineCommand::ArmPunch { track } => {
+                if let Some(t) = self.tracks.get_mut(track) {
+                    t.arm_punch();
+                }
+            }
+            EngineCommand::CancelPunch { track } => {
+                if let Some(t) = self.tracks.get_mut(track) {
+                    t.cancel_punch();
+                }
+            }
+            EngineCommand::Punch {
+                track,
+                frames,
+                sample_rate,
+            } => {
+                if let Some(t) = self.tracks.get_mut(track) {
+                    t.punch(&frames, sample_rate);
+                }
+            }
+            EngineCommand::SelectTake { track, take } => {
+                if let Some(t) = self.tracks.get_mut(track) {
+                    t.select_take(take);
+                }
+            }
+            EngineCommand::StartResample => {
+                self.tracks.iter_mut().for_each(Track::start_mirror);
+                self.resampler = Some(Resampler {
+                    frames: Vec::new(),
+                    started: Instant::now(),
+                    clicks: Vec::new(),
+                });
+            }
+            EngineCommand::StopResample { reply } => {
+                self.resample();
+                for track in self.tracks.iter_mut() {
+                    track.mirror = None;
+                }
+                let frames = self
+                    .resampler
+                    .take()
+                    .map(|resampler| resampler.frames)
+                    .unwrap_or_default();
+                let _s = reply.send(frames);
+            }
+            EngineCommand::Click { accent } => {
+                if let Some(resampler) = self.resampler.as_mut() {
+                    let length = (CLICK_SECONDS * RESAMPLE_SAMPLE_RATE as f64).ceil() as usize;
+                    resampler.clicks.push((click_sound(accent), length));
+                }
+                self.mixer.play(click_sound(accent));
+            }
+            EngineCommand::Pause => self.tracks.iter_mut().for_each(Track::pause),
+            EngineCommand::Resume => self.tracks.iter_mut().for_each(Track::resume),
+            EngineCommand::Stop => self.tracks.iter_mut().for_each(Track::stop),
+        }
+    }
+}
@@ -0,0 +1,280 @@
+use crate::decoder::{PeakBin, PeakPyramid};
+use anyhow::anyhow;
+use std::{
+    collections::hash_map::DefaultHasher,
+    fs::{self, File},
+    hash::{Hash, Hasher},
+    io::{BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+const CACHE_DIR: &str = "useless-looper/peaks";
+const MAGIC: &[u8; 4] = b"ULPK";
+// Bump when the layout below or the way pyramids are built changes.
+const VERSION: u32 = 1;
+// Limits on what is read back, so a damaged file is rejected instead of trusted.
+const MAX_PATH_LENGTH: u64 = 4096;
+const MAX_LEVELS: u64 = 48;
+
+// What a cached file is checked against, so an edited file is decoded again.
+#[derive(Debug, PartialEq, Hash)]
+struct CacheKey {
+    path: String,
+    size: u64,
+    modified: u64,
+}
+
+impl CacheKey {
+    fn new(path: &Path) -> anyhow::Result<Self> {
+        let path = fs::canonicalize(path)?;
+        let metadata = fs::metadata(&path)?;
+        Ok(Self {
+            path: path.to_string_lossy().into_owned(),
+            size: metadata.len(),
+            modified: metadata.modified()?.duration_since(UNIX_EPOCH)?.as_nanos() as u64,
+        })
+    }
+
+    fn cache_path(&self) -> anyhow::Result<PathBuf> {
+        let cache_dir = dirs::cache_dir().ok_or_else(|| anyhow!("No cache directory"))?;
+        let mut hasher = DefaultHasher::new();
+        self.hash(&mut hasher);
+        Ok(cache_dir
+            .join(CACHE_DIR)
+            .join(format!("{:016x}.peaks", hasher.finish())))
+    }
+}
+
+// Pyramids stored for `path` while it is unchanged, in the groups they were stored in.
+pub fn load(path: &Path) -> Option<Vec<Vec<PeakPyramid>>> {
+    let key = CacheKey::new(path).ok()?;
+    let mut reader = BufReader::new(File::open(key.cache_path().ok()?).ok()?);
+    read_groups(&mut reader, &key).ok()
+}
+
+pub fn store(path: &Path, groups: &[&[PeakPyramid]]) -> anyhow::Result<()> {
+    let key = CacheKey::new(path)?;
+    let cache_path = key.cache_path()?;
+    if let Some(dir) = cache_path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    // Written aside first so a half-written file is never read back.
+    let temp_path = cache_path.with_extension("tmp");
+    let mut writer = BufWriter::new(File::create(&temp_path)?);
+    write_groups(&mut writer, &key, groups)?;
+    writer.flush()?;
+    drop(writer);
+    fs::rename(temp_path, cache_path)?;
+    Ok(())
+}
+
+fn write_groups(
+    writer: &mut impl Write,
+    key: &CacheKey,
+    groups: &[&[PeakPyramid]],
+) -> anyhow::Result<()> {
+    writer.write_all(MAGIC)?;
+    writer.write_all(&VERSION.to_le_bytes())?;
+    write_u64(writer, key.path.len() as u64)?;
+    writer.write_all(key.path.as_bytes())?;
+    write_u64(writer, key.size)?;
+    write_u64(writer, key.modified)?;
+    write_u64(writer, groups.len() as u64)?;
+    for group in groups {
+        write_u64(writer, group.len() as u64)?;
+        for pyramid in group.iter() {
+            write_u64(writer, pyramid.sample_count() as u64)?;
+            write_u64(writer, pyramid.levels().len() as u64)?;
+            for level in pyramid.levels() {
+                write_u64(writer, level.len() as u64)?;
+                for bin in level {
+                    for value in [bin.min, bin.max, bin.rms] {
+                        writer.write_all(&value.to_le_bytes())?;
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn read_groups(reader: &mut impl Read, key: &CacheKey) -> anyhow::Result<Vec<Vec<PeakPyramid>>> {
+    let mut magic = [0; 4];
+    reader.read_exact(&mut magic)?;
+    let mut version = [0; 4];
+    reader.read_exact(&mut version)?;
+    if magic != *MAGIC || u32::from_le_bytes(version) != VERSION {
+        return Err(anyhow!("Not a peak cache of this version"));
+    }
+    let path_length = read_u64(reader)?;
+    if path_length > MAX_PATH_LENGTH {
+        return Err(anyhow!("Peak cache path is {} bytes long", path_length));
+    }
+    let mut path = vec![0; path_length as usize];
+    reader.read_exact(&mut path)?;
+    let stored_key = CacheKey {
+        path: String::from_utf8(path)?,
+        size: read_u64(reader)?,
+        modified: read_u64(reader)?,
+    };
+    if stored_key != *key {
+        return Err(anyhow!("Peak cache is for another file"));
+    }
+    let mut groups = Vec::new();
+    for _ in 0..read_u64(reader)? {
+        let mut group = Vec::new();
+        for _ in 0..read_u64(reader)? {
+            group.push(read_pyramid(reader)?);
+        }
+        groups.push(group);
+    }
+    Ok(groups)
+}
+
+fn read_pyramid(reader: &mut impl Read) -> anyhow::Result<PeakPyramid> {
+    let sample_count = read_u64(reader)? as usize;
+    let level_count = read_u64(reader)?;
+    if level_count == 0 || level_count > MAX_LEVELS {
+        return Err(anyhow!("Peak cache pyramid has {} levels", level_count));
+    }
+    let mut levels = Vec::new();
+    for _ in 0..level_count {
+        let bin_count = read_u64(reader)?;
+        if bin_count == 0 {
+            return Err(anyhow!("Peak cache pyramid has an empty level"));
+        }
+        let mut level = Vec::new();
+        for _ in 0..bin_count {
+            level.push(PeakBin {
+                min: read_f32(reader)?,
+                max: read_f32(reader)?,
+                rms: read_f32(reader)?,
+            });
+        }
+        levels.push(level);
+    }
+    Ok(PeakPyramid::from_levels(sample_count, levels))
+}
+
+fn write_u64(writer: &mut impl Write, value: u64) -> anyhow::Result<()> {
+    writer.write_all(&value.to_le_bytes())?;
+    Ok(())
+}
+
+fn read_u64(reader: &mut impl Read) -> anyhow::Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn read_f32(reader: &mut impl Read) -> anyhow::Result<f32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(f32::from_le_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(path: &str) -> CacheKey {
+        CacheKey {
+            path: path.to_string(),
+            size: 1234,
+            modified: 5678,
+        }
+    }
+
+    fn bin(value: f32) -> PeakBin {
+        PeakBin {
+            min: -value,
+            max: value,
+            rms: value / 2.0,
+        }
+    }
+
+    fn written(key: &CacheKey, groups: &[&[PeakPyramid]]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        write_groups(&mut bytes, key, groups).unwrap();
+        bytes
+    }
+
+    fn read(bytes: &[u8], key: &CacheKey) -> anyhow::Result<Vec<Vec<PeakPyramid>>> {
+        read_groups(&mut &bytes[..], key)
+    }
+
+    // Every bin of every pyramid, to compare what was stored with what was read back.
+    fn contents(groups: &[&[PeakPyramid]]) -> Vec<(usize, Vec<Vec<(f32, f32, f32)>>)> {
+        groups
+            .iter()
+            .flat_map(|group| group.iter())
+            .map(|pyramid| {
+                let levels: Vec<Vec<_>> = pyramid
+                    .levels()
+                    .iter()
+                    .map(|level| {
+                        level
+                            .iter()
+                            .map(|bin| (bin.min, bin.max, bin.rms))
+                            .collect()
+                    })
+                    .collect();
+                (pyramid.sample_count(), levels)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn reads_back_what_was_written() {
+        let key = key("/audio/take.wav");
+        let mixed = [PeakPyramid::from_levels(
+            512,
+            vec![vec![bin(0.5), bin(0.25)], vec![bin(0.5)]],
+        )];
+        let channels = [
+            PeakPyramid::from_levels(256, vec![vec![bin(0.75)]]),
+            PeakPyramid::from_levels(256, vec![vec![bin(0.125)]]),
+        ];
+        let groups: [&[PeakPyramid]; 3] = [&mixed, &channels, &[]];
+        let read_back = read(&written(&key, &groups), &key).unwrap();
+        let read_back: Vec<&[PeakPyramid]> = read_back.iter().map(Vec::as_slice).collect();
+        assert_eq!(read_back.len(), 3);
+        assert_eq!(contents(&read_back), contents(&groups));
+    }
+
+    #[test]
+    fn rejects_another_file() {
+        let pyramids = [PeakPyramid::from_levels(256, vec![vec![bin(0.5)]])];
+        let bytes = written(&key("/audio/take.wav"), &[&pyramids]);
+        assert!(read(&bytes, &key("/audio/other.wav")).is_err());
+    }
+
+    #[test]
+    fn rejects_truncated_files() {
+        let key = key("/audio/take.wav");
+        let pyramids = [PeakPyramid::from_levels(256, vec![vec![bin(0.5)]])];
+        let bytes = written(&key, &[&pyramids]);
+        assert!(read(&bytes[..bytes.len() - 1], &key).is_err());
+        assert!(read(&bytes[..MAGIC.len()], &key).is_err());
+    }
+
+    #[test]
+    fn rejects_overlong_paths() {
+        let key = key(&"a".repeat(MAX_PATH_LENGTH as usize + 1));
+        let pyramids = [PeakPyramid::from_levels(256, vec![vec![bin(0.5)]])];
+        assert!(read(&written(&key, &[&pyramids]), &key).is_err());
+    }
+
+    #[test]
+    fn rejects_pyramids_without_bins() {
+        let key = key("/audio/take.wav");
+        let no_levels = [PeakPyramid::from_levels(256, Vec::new())];
+        assert!(read(&written(&key, &[&no_levels]), &key).is_err());
+        let empty_level = [PeakPyramid::from_levels(
+            256,
+            vec![vec![bin(0.5)], Vec::new()],
+        )];
+        assert!(read(&written(&key, &[&empty_level]), &key).is_err());
+    }
+}
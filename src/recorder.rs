@@ -1,36 +1,768 @@
+use crate::utils::interleaved_to_frames;
+use anyhow::anyhow;
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
-use hound;
+use cpal::{FromSample, Sample, SizedSample};
+use hound::{WavSpec, WavWriter};
+use kittyaudio::{Frame, Sound};
+use rtrb::{Consumer, RingBuffer};
+use std::{
+    collections::VecDeque,
+    fmt,
+    fs::File,
+    io::BufWriter,
+    ops::Range,
+    path::PathBuf,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
 
-pub fn _record() {
-    let host = cpal::default_host();
-        let device = host.default_input_device().expect("Failed to get default input device");
-        let config = device.default_input_config().expect("Failed to get default input config");
-        let sample_format = config.sample_format();
+// How much input the ring buffer holds before the audio callback starts dropping samples.
+const RING_BUFFER_SECONDS: usize = 2;
+const WRITER_INTERVAL: Duration = Duration::from_millis(5);
+// How much input is kept around for retrospective capture.
+const HISTORY_SECONDS: usize = 30;
+// Silence at least this long separates two phrases.
+const PHRASE_GAP_SECONDS: f64 = 0.5;
+pub const DEFAULT_CAPTURE_SECONDS: f64 = 8.0;
+pub const DEFAULT_PHRASE_THRESHOLD: f32 = 0.02;
+pub const DEFAULT_COUNT_IN_BEATS: u32 = 4;
+pub const DEFAULT_COUNT_IN_BPM: f64 = 120.0;
+const TRIGGER_LEVELS: [f32; 4] = [0.01, 0.02, 0.05, 0.1];
+const MAX_COUNT_IN_BEATS: u32 = 8;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum ChannelMode {
+    // Only the first selected input channel.
+    Single,
+    // The selected pair of input channels averaged into one.
+    Downmix,
+    // The selected pair of input channels kept as left and right.
+    #[default]
+    Stereo,
+}
+
+impl ChannelMode {
+    pub fn next(self) -> Self {
+        match self {
+            ChannelMode::Single => ChannelMode::Downmix,
+            ChannelMode::Downmix => ChannelMode::Stereo,
+            ChannelMode::Stereo => ChannelMode::Single,
+        }
+    }
+}
+
+// Which device channels end up in a recording, and how.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct InputRouting {
+    pub first_channel: usize,
+    pub mode: ChannelMode,
+}
+
+impl InputRouting {
+    pub fn output_channels(&self) -> u16 {
+        match self.mode {
+            ChannelMode::Single | ChannelMode::Downmix => 1,
+            ChannelMode::Stereo => 2,
+        }
+    }
+
+    // Moved back until all its channels exist on the device, a mono device only offering
+    // its one channel, so the label always names the channels that are recorded.
+    pub fn fit(self, device_channels: usize) -> Self {
+        if device_channels < 2 {
+            return Self {
+                first_channel: 0,
+                mode: ChannelMode::Single,
+            };
+        }
+        let width = match self.mode {
+            ChannelMode::Single => 1,
+            ChannelMode::Downmix | ChannelMode::Stereo => 2,
+        };
+        Self {
+            first_channel: self.first_channel.min(device_channels - width),
+            mode: self.mode,
+        }
+    }
+
+    // Hands each routed frame to `push` whole, so its channels can't be split up.
+    fn route<T>(&self, data: &[T], device_channels: usize, mut push: impl FnMut(&[f32]))
+    where
+        T: SizedSample,
+        f32: FromSample<T>,
+    {
+        let first = self.first_channel.min(device_channels - 1);
+        let second = if first + 1 < device_channels {
+            first + 1
+        } else {
+            first
+        };
+        for frame in data.chunks_exact(device_channels) {
+            let left = frame[first].to_sample::<f32>();
+            let right = frame[second].to_sample::<f32>();
+            match self.mode {
+                ChannelMode::Single => push(&[left]),
+                ChannelMode::Downmix => push(&[(left + right) * 0.5]),
+                ChannelMode::Stereo => push(&[left, right]),
+            }
+        }
+    }
+}
+
+impl fmt::Display for ChannelMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChannelMode::Single => write!(f, "MONO"),
+            ChannelMode::Downmix => write!(f, "SUM"),
+            ChannelMode::Stereo => write!(f, "STEREO"),
+        }
+    }
+}
+
+impl fmt::Display for InputRouting {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.mode {
+            ChannelMode::Single => write!(f, "IN {}", self.first_channel + 1),
+            ChannelMode::Downmix | ChannelMode::Stereo => {
+                write!(
+                    f,
+                    "IN {}+{}",
+                    self.first_channel + 1,
+                    self.first_channel + 2
+                )
+            }
+        }
+    }
+}
+
+pub fn input_channel_count() -> anyhow::Result<u16> {
+    let device = cpal::default_host()
+        .default_input_device()
+        .ok_or_else(|| anyhow!("Failed to get default input device"))?;
+    Ok(device.default_input_config()?.channels())
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum RecordingFormat {
+    #[default]
+    Int16,
+    Int24,
+    Float32,
+}
+
+impl RecordingFormat {
+    pub fn spec(self, channels: u16, sample_rate: u32) -> WavSpec {
+        let (bits_per_sample, sample_format) = match self {
+            RecordingFormat::Int16 => (16, hound::SampleFormat::Int),
+            RecordingFormat::Int24 => (24, hound::SampleFormat::Int),
+            RecordingFormat::Float32 => (32, hound::SampleFormat::Float),
+        };
+        WavSpec {
+            channels,
+            sample_rate,
+            bits_per_sample,
+            sample_format,
+        }
+    }
+}
+
+impl fmt::Display for RecordingFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecordingFormat::Int16 => write!(f, "16"),
+            RecordingFormat::Int24 => write!(f, "24"),
+            RecordingFormat::Float32 => write!(f, "32f"),
+        }
+    }
+}
+
+impl FromStr for RecordingFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "16" => Ok(RecordingFormat::Int16),
+            "24" => Ok(RecordingFormat::Int24),
+            "32f" | "32" => Ok(RecordingFormat::Float32),
+            _ => Err(anyhow!(
+                "Unknown recording format {}, expected 16, 24 or 32f",
+                s
+            )),
+        }
+    }
+}
+
+// Writes samples in the chosen format, with TPDF dither whenever the bit depth is reduced.
+struct SampleEncoder {
+    format: RecordingFormat,
+    // Only set when the file has fewer bits than the device delivers.
+    dither: bool,
+    noise_state: u32,
+}
+
+impl SampleEncoder {
+    fn new(format: RecordingFormat, source_bits: u16) -> Self {
+        let bits_per_sample = format.spec(1, 1).bits_per_sample;
+        Self {
+            format,
+            dither: format != RecordingFormat::Float32 && bits_per_sample < source_bits,
+            noise_state: 0x9E37_79B9,
+        }
+    }
+
+    // Xorshift is plenty for dither noise and keeps the writer free of extra dependencies.
+    fn next_uniform(&mut self) -> f32 {
+        self.noise_state ^= self.noise_state << 13;
+        self.noise_state ^= self.noise_state >> 17;
+        self.noise_state ^= self.noise_state << 5;
+        self.noise_state as f32 / u32::MAX as f32
+    }
+
+    fn quantize(&mut self, sample: f32, full_scale: f32) -> i32 {
+        let dither = match self.dither {
+            true => self.next_uniform() - self.next_uniform(),
+            false => 0.0,
+        };
+        (sample * full_scale + dither)
+            .round()
+            .clamp(-full_scale - 1.0, full_scale) as i32
+    }
+
+    fn write(
+        &mut self,
+        writer: &mut WavWriter<BufWriter<File>>,
+        sample: f32,
+    ) -> Result<(), hound::Error> {
+        match self.format {
+            RecordingFormat::Int16 => {
+                let sample = self.quantize(sample, i16::MAX as f32) as i16;
+                writer.write_sample(sample)
+            }
+            RecordingFormat::Int24 => {
+                let sample = self.quantize(sample, 8_388_607.0);
+                writer.write_sample(sample)
+            }
+            RecordingFormat::Float32 => writer.write_sample(sample),
+        }
+    }
+}
+
+// Interleaved samples captured from the input device, already routed to mono or stereo.
+pub struct Recording {
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub samples: Vec<f32>,
+}
+
+impl Recording {
+    pub fn from_frames(sample_rate: u32, frames: &[Frame]) -> Self {
+        Self {
+            sample_rate,
+            channels: 2,
+            samples: frames
+                .iter()
+                .flat_map(|frame| [frame.left, frame.right])
+                .collect(),
+        }
+    }
+
+    pub fn duration(&self) -> f64 {
+        self.frame_count() as f64 / self.sample_rate as f64
+    }
+
+    pub fn frame_count(&self) -> usize {
+        self.samples.len() / self.channels.max(1) as usize
+    }
+
+    pub fn frames(&self) -> Vec<Frame> {
+        interleaved_to_frames(&self.samples, self.channels)
+    }
+
+    pub fn to_sound(&self) -> Sound {
+        Sound::from_frames(self.sample_rate, &self.frames())
+    }
+}
+
+// How a take begins once recording is requested.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum RecordTrigger {
+    #[default]
+    Immediate,
+    // Armed until the input reaches this level.
+    Level(f32),
+    // Starts after a metronome count-in.
+    CountIn {
+        beats: u32,
+        bpm: f64,
+    },
+}
+
+impl RecordTrigger {
+    pub fn next(self) -> Self {
+        match self {
+            RecordTrigger::Immediate => RecordTrigger::Level(DEFAULT_PHRASE_THRESHOLD),
+            RecordTrigger::Level(_) => RecordTrigger::CountIn {
+                beats: DEFAULT_COUNT_IN_BEATS,
+                bpm: DEFAULT_COUNT_IN_BPM,
+            },
+            RecordTrigger::CountIn { .. } => RecordTrigger::Immediate,
+        }
+    }
 
-        let spec = hound::WavSpec {
-            channels: 1,
-            sample_rate: config.sample_rate().0,
-            bits_per_sample: 16,
-            sample_format: hound::SampleFormat::Int,
+    // Steps the level or the number of beats, wrapping around.
+    pub fn adjust(self) -> Self {
+        match self {
+            RecordTrigger::Immediate => self,
+            RecordTrigger::Level(level) => {
+                let next = TRIGGER_LEVELS
+                    .iter()
+                    .find(|&&l| l > level)
+                    .unwrap_or(&TRIGGER_LEVELS[0]);
+                RecordTrigger::Level(*next)
+            }
+            RecordTrigger::CountIn { beats, bpm } => RecordTrigger::CountIn {
+                beats: beats % MAX_COUNT_IN_BEATS + 1,
+                bpm,
+            },
+        }
+    }
+}
+
+impl fmt::Display for RecordTrigger {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecordTrigger::Immediate => write!(f, "FREE"),
+            RecordTrigger::Level(level) => write!(f, "LEVEL {}", level),
+            RecordTrigger::CountIn { beats, .. } => write!(f, "COUNT {}", beats),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum CaptureMode {
+    LastSeconds(f64),
+    // The last stretch of input above the threshold, bounded by silence.
+    LastPhrase { threshold: f32 },
+}
+
+#[derive(Default)]
+struct RecorderStats {
+    // Set while a take is armed or running, the input in between isn't counted.
+    taking: AtomicBool,
+    dropped_samples: AtomicUsize,
+    overruns: AtomicUsize,
+    clipped_samples: AtomicUsize,
+}
+
+impl RecorderStats {
+    fn reset(&self) {
+        self.dropped_samples.store(0, Ordering::Relaxed);
+        self.overruns.store(0, Ordering::Relaxed);
+        self.clipped_samples.store(0, Ordering::Relaxed);
+    }
+}
+
+// Shared between the writer thread, which fills it, and the UI, which starts and stops takes.
+#[derive(Default)]
+struct CaptureState {
+    history: VecDeque<f32>,
+    history_capacity: usize,
+    channels: usize,
+    // Samples pushed since the input was opened, to keep the trigger on a frame boundary.
+    position: usize,
+    // Some while waiting for the input to reach this level before the take starts.
+    armed: Option<f32>,
+    // Some while a take is being recorded.
+    take: Option<Vec<f32>>,
+}
+
+// The file a take is streamed to, kept out of `CaptureState` so that disk writes never hold it.
+type WavOutput = Option<(WavWriter<BufWriter<File>>, SampleEncoder)>;
+
+impl CaptureState {
+    // Adds input to the history and the take, returning the part of it the take got.
+    fn push<'a>(&mut self, samples: &'a [f32]) -> &'a [f32] {
+        self.history.extend(samples);
+        self.trim_history();
+        let position = self.position;
+        self.position += samples.len();
+
+        let samples = match self.armed {
+            Some(threshold) => {
+                let Some(loud) = samples.iter().position(|sample| sample.abs() >= threshold) else {
+                    return &[];
+                };
+                let channels = self.channels.max(1);
+                let start = loud + (channels - (position + loud) % channels) % channels;
+                self.armed = None;
+                self.take = Some(Vec::new());
+                &samples[start.min(samples.len())..]
+            }
+            None => samples,
+        };
+        let Some(take) = self.take.as_mut() else {
+            return &[];
         };
+        take.extend_from_slice(samples);
+        samples
+    }
+
+    fn trim_history(&mut self) {
+        let overflow = self.history.len().saturating_sub(self.history_capacity);
+        self.history.drain(..overflow);
+    }
+}
+
+// Keeps the input device open; takes and retrospective captures are cut from its stream.
+#[derive(Default)]
+pub struct Recorder {
+    stream: Option<cpal::Stream>,
+    writer: Option<JoinHandle<()>>,
+    state: Arc<Mutex<CaptureState>>,
+    // Only the writer thread and starting or stopping a take lock this.
+    wav_output: Arc<Mutex<WavOutput>>,
+    stats: Arc<RecorderStats>,
+    sample_rate: u32,
+    channels: u16,
+    // Bits per sample the device delivers, to tell whether writing the file reduces them.
+    source_bits: u16,
+    routing: InputRouting,
+    // When set, takes are also streamed to this WAV file by the writer thread.
+    pub output_path: Option<PathBuf>,
+    pub format: RecordingFormat,
+}
+
+impl Recorder {
+    pub fn is_open(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.state.lock().unwrap().take.is_some()
+    }
+
+    // Waiting for the input level to start a take.
+    pub fn is_armed(&self) -> bool {
+        self.state.lock().unwrap().armed.is_some()
+    }
+
+    pub fn routing(&self) -> InputRouting {
+        self.routing
+    }
+
+    // Changing the routing reopens the input, unless a take is running.
+    pub fn set_routing(&mut self, routing: InputRouting) -> anyhow::Result<()> {
+        self.routing = routing.fit(input_channel_count()? as usize);
+        if self.is_open() && !self.is_recording() && !self.is_armed() {
+            self.close();
+            self.open()?;
+        }
+        Ok(())
+    }
 
-        let mut writer = hound::WavWriter::create("recorded.wav", spec).unwrap();
+    pub fn dropped_samples(&self) -> usize {
+        self.stats.dropped_samples.load(Ordering::Relaxed)
+    }
 
-        let err_fn = |err| eprintln!("an error occurred on stream: {}", err);
+    pub fn overruns(&self) -> usize {
+        self.stats.overruns.load(Ordering::Relaxed)
+    }
 
+    // Samples that reached or exceeded full scale on the input.
+    pub fn clipped_samples(&self) -> usize {
+        self.stats.clipped_samples.load(Ordering::Relaxed)
+    }
+
+    pub fn open(&mut self) -> anyhow::Result<()> {
+        if self.is_open() {
+            return Ok(());
+        }
+        let host = cpal::default_host();
+        let device = host
+            .default_input_device()
+            .ok_or_else(|| anyhow!("Failed to get default input device"))?;
+        let config = device.default_input_config()?;
+        let sample_format = config.sample_format();
+        let routing = self.routing.fit(config.channels() as usize);
+        self.routing = routing;
+        self.sample_rate = config.sample_rate().0;
+        self.source_bits = (sample_format.sample_size() * 8) as u16;
+        self.channels = routing.output_channels();
+        self.stats.reset();
+        {
+            let mut state = self.state.lock().unwrap();
+            state.history.clear();
+            state.channels = self.channels as usize;
+            state.position = 0;
+            state.history_capacity =
+                self.sample_rate as usize * self.channels as usize * HISTORY_SECONDS;
+        }
+
+        let capacity = self.sample_rate as usize * self.channels as usize * RING_BUFFER_SECONDS;
+        let (producer, consumer) = RingBuffer::<f32>::new(capacity);
+        let config = config.into();
+        let stats = self.stats.clone();
         let stream = match sample_format {
-            cpal::SampleFormat::F32 => device.build_input_stream(&config.into(), move |data: &[f32], _: &cpal::InputCallbackInfo| {
-                for &sample in data {
-                    let amplitude = (sample * std::i16::MAX as f32) as i16;
-                    writer.write_sample(amplitude).unwrap();
+            cpal::SampleFormat::F32 => {
+                build_input_stream::<f32>(&device, &config, routing, producer, stats)?
+            }
+            cpal::SampleFormat::I16 => {
+                build_input_stream::<i16>(&device, &config, routing, producer, stats)?
+            }
+            cpal::SampleFormat::U16 => {
+                build_input_stream::<u16>(&device, &config, routing, producer, stats)?
+            }
+            sample_format => return Err(anyhow!("Unsupported sample format {}", sample_format)),
+        };
+        self.writer = Some(spawn_writer(
+            consumer,
+            self.state.clone(),
+            self.wav_output.clone(),
+            self.stats.clone(),
+        ));
+        stream.play()?;
+        self.stream = Some(stream);
+        Ok(())
+    }
+
+    pub fn close(&mut self) {
+        // Dropping the stream drops the producer, which lets the writer drain and finish.
+        self.stream = None;
+        if let Some(writer) = self.writer.take() {
+            if writer.join().is_err() {
+                eprintln!("Recording writer thread panicked");
+            }
+        }
+    }
+
+    pub fn start(&mut self) -> anyhow::Result<()> {
+        self.begin(None)
+    }
+
+    // Opens a take that only starts recording once the input reaches `threshold`.
+    pub fn arm(&mut self, threshold: f32) -> anyhow::Result<()> {
+        self.begin(Some(threshold))
+    }
+
+    fn begin(&mut self, threshold: Option<f32>) -> anyhow::Result<()> {
+        self.open()?;
+        if self.is_recording() || self.is_armed() {
+            return Ok(());
+        }
+        let wav_writer = match &self.output_path {
+            Some(path) => Some((
+                WavWriter::create(path, self.format.spec(self.channels, self.sample_rate))?,
+                SampleEncoder::new(self.format, self.source_bits),
+            )),
+            None => None,
+        };
+        self.stats.reset();
+        self.stats.taking.store(true, Ordering::Relaxed);
+        // The file is in place before the take starts, so the writer thread has it for the first input.
+        *self.wav_output.lock().unwrap() = wav_writer;
+        let mut state = self.state.lock().unwrap();
+        match threshold {
+            Some(threshold) => state.armed = Some(threshold),
+            None => state.take = Some(Vec::new()),
+        }
+        Ok(())
+    }
+
+    // Ends the take, or disarms the recorder if the input never reached the threshold.
+    pub fn stop(&mut self) -> Option<Recording> {
+        let take = {
+            let mut state = self.state.lock().unwrap();
+            state.armed = None;
+            state.take.take()
+        };
+        self.stats.taking.store(false, Ordering::Relaxed);
+        // Waits for the writer thread to write whatever the take got before it ended.
+        let wav_writer = self.wav_output.lock().unwrap().take();
+        if let Some((writer, _)) = wav_writer {
+            if let Err(e) = writer.finalize() {
+                eprintln!("Error finalizing recording: {:?}", e);
+            }
+        }
+        self.recording(take?)
+    }
+
+    // Hands over everything captured so far while the take keeps running.
+    pub fn take_recording(&mut self) -> Option<Recording> {
+        let samples = std::mem::take(self.state.lock().unwrap().take.as_mut()?);
+        self.recording(samples)
+    }
+
+    // Cuts a recording out of the input that has already gone by.
+    pub fn capture(&self, mode: CaptureMode) -> Option<Recording> {
+        let channels = self.channels.max(1) as usize;
+        // Borrowed out of the shared state, so the input keeps flowing while it is searched.
+        let mut history = std::mem::take(&mut self.state.lock().unwrap().history);
+        let samples = {
+            let history = history.make_contiguous();
+            let frame_count = history.len() / channels;
+            let frames = match mode {
+                CaptureMode::LastSeconds(seconds) => {
+                    let length = ((seconds * self.sample_rate as f64) as usize).min(frame_count);
+                    Some(frame_count - length..frame_count)
                 }
-            }, err_fn, None),
-            _ => unimplemented!(),
-        }.unwrap();
+                CaptureMode::LastPhrase { threshold } => last_phrase(
+                    history,
+                    channels,
+                    (PHRASE_GAP_SECONDS * self.sample_rate as f64) as usize,
+                    threshold,
+                ),
+            };
+            frames.map(|frames| history[frames.start * channels..frames.end * channels].to_vec())
+        };
+        {
+            let mut state = self.state.lock().unwrap();
+            let arrived = std::mem::replace(&mut state.history, history);
+            state.history.extend(arrived);
+            state.trim_history();
+        }
+        self.recording(samples?)
+    }
+
+    fn recording(&self, samples: Vec<f32>) -> Option<Recording> {
+        if samples.is_empty() {
+            return None;
+        }
+        Some(Recording {
+            sample_rate: self.sample_rate,
+            channels: self.channels,
+            samples,
+        })
+    }
+}
+
+// Frame range of the last phrase: back from the last loud frame until `gap` quiet frames in a row.
+fn last_phrase(
+    samples: &[f32],
+    channels: usize,
+    gap: usize,
+    threshold: f32,
+) -> Option<Range<usize>> {
+    let is_loud = |frame: usize| {
+        samples[frame * channels..(frame + 1) * channels]
+            .iter()
+            .any(|sample| sample.abs() >= threshold)
+    };
+    let frame_count = samples.len() / channels;
+    let end = (0..frame_count).rev().find(|&frame| is_loud(frame))? + 1;
+    let mut start = end - 1;
+    let mut quiet_frames = 0;
+    for frame in (0..end).rev() {
+        if is_loud(frame) {
+            start = frame;
+            quiet_frames = 0;
+        } else {
+            quiet_frames += 1;
+            if quiet_frames >= gap {
+                break;
+            }
+        }
+    }
+    Some(start..end)
+}
 
-        stream.play().unwrap();
+fn build_input_stream<T>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    routing: InputRouting,
+    mut producer: rtrb::Producer<f32>,
+    stats: Arc<RecorderStats>,
+) -> anyhow::Result<cpal::Stream>
+where
+    T: SizedSample,
+    f32: FromSample<T>,
+{
+    let device_channels = config.channels.max(1) as usize;
+    let err_fn = |err| eprintln!("an error occurred on stream: {}", err);
+    let stream = device.build_input_stream(
+        config,
+        move |data: &[T], _: &cpal::InputCallbackInfo| {
+            // Nothing in here may block or allocate, a full ring buffer just drops whole frames
+            // so that the channels stay in order.
+            let mut dropped = 0;
+            routing.route(data, device_channels, |frame| {
+                if producer.slots() < frame.len() {
+                    dropped += frame.len();
+                    return;
+                }
+                for &sample in frame {
+                    let _s = producer.push(sample);
+                }
+            });
+            if dropped > 0 && stats.taking.load(Ordering::Relaxed) {
+                stats.dropped_samples.fetch_add(dropped, Ordering::Relaxed);
+                stats.overruns.fetch_add(1, Ordering::Relaxed);
+            }
+        },
+        err_fn,
+        None,
+    )?;
+    Ok(stream)
+}
 
-        std::thread::sleep(std::time::Duration::from_secs(3));
+fn spawn_writer(
+    mut consumer: Consumer<f32>,
+    state: Arc<Mutex<CaptureState>>,
+    wav_output: Arc<Mutex<WavOutput>>,
+    stats: Arc<RecorderStats>,
+) -> JoinHandle<()> {
+    thread::spawn(move || loop {
+        let abandoned = consumer.is_abandoned();
+        let available = consumer.slots();
+        if available == 0 {
+            if abandoned {
+                break;
+            }
+            thread::sleep(WRITER_INTERVAL);
+            continue;
+        }
+        let Ok(chunk) = consumer.read_chunk(available) else {
+            continue;
+        };
+        let (first, second) = chunk.as_slices();
+        // The file stays locked from before the take gets the input until it is written,
+        // so stopping a take can't finalize the file with input still on its way.
+        if let Ok(mut wav_output) = wav_output.lock() {
+            let taken = match state.lock() {
+                Ok(mut state) => [state.push(first), state.push(second)],
+                Err(_) => [&[][..], &[][..]],
+            };
+            // Only what went into the take counts as clipped.
+            let clipped = taken
+                .iter()
+                .flat_map(|samples| samples.iter())
+                .filter(|sample| sample.abs() >= 1.0)
+                .count();
+            if clipped > 0 {
+                stats.clipped_samples.fetch_add(clipped, Ordering::Relaxed);
+            }
+            if let Some((writer, encoder)) = wav_output.as_mut() {
+                if let Err(e) = write_samples(writer, encoder, taken.into_iter().flatten()) {
+                    eprintln!("Error writing recording: {:?}", e);
+                    *wav_output = None;
+                }
+            }
+        }
+        chunk.commit_all();
+    })
+}
 
-        stream.pause().unwrap();
+fn write_samples<'a>(
+    writer: &mut WavWriter<BufWriter<File>>,
+    encoder: &mut SampleEncoder,
+    samples: impl Iterator<Item = &'a f32>,
+) -> Result<(), hound::Error> {
+    for &sample in samples {
+        encoder.write(writer, sample)?;
+    }
+    Ok(())
 }
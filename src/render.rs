@@ -0,0 +1,165 @@
+use crate::engine::loop_bounds;
+use anyhow::anyhow;
+use hound::{SampleFormat, WavSpec, WavWriter};
+use kittyaudio::{Frame, PlaybackRate, Sound};
+use std::path::Path;
+
+pub const DEFAULT_RENDER_SAMPLE_RATE: u32 = 44100;
+
+#[derive(Debug, Clone, Copy)]
+pub struct RenderSettings {
+    pub loop_start: f64,
+    pub loop_length: f64,
+    pub pitch: f64,
+    pub seconds: f64,
+    pub sample_rate: u32,
+}
+
+// Plays the sound through kittyaudio without a device, with the loop applied the way the engine applies it.
+pub fn render_loop(mut sound: Sound, settings: RenderSettings) -> anyhow::Result<Vec<Frame>> {
+    let sound_duration = sound.duration().as_secs_f64();
+    let (lower_bound, upper_bound) =
+        loop_bounds(settings.loop_start, settings.loop_length, sound_duration).ok_or_else(
+            || {
+                anyhow!(
+                    "loop start {}s is past the end of the sound ({}s)",
+                    settings.loop_start,
+                    sound_duration
+                )
+            },
+        )?;
+
+    sound.set_loop_enabled(true);
+    sound.set_loop(lower_bound..=upper_bound);
+    sound.seek_to(lower_bound);
+    sound.set_playback_rate(PlaybackRate::Factor(settings.pitch));
+
+    let frame_count = (settings.seconds * settings.sample_rate as f64).round() as usize;
+    Ok((0..frame_count)
+        .map(|_| sound.next_frame(settings.sample_rate))
+        .collect())
+}
+
+pub fn write_wav<P: AsRef<Path>>(
+    path: P,
+    frames: &[Frame],
+    sample_rate: u32,
+) -> anyhow::Result<()> {
+    let spec = WavSpec {
+        channels: 2,
+        sample_rate,
+        bits_per_sample: 32,
+        sample_format: SampleFormat::Float,
+    };
+    let mut writer = WavWriter::create(path, spec)?;
+    for frame in frames {
+        writer.write_sample(frame.left)?;
+        writer.write_sample(frame.right)?;
+    }
+    writer.finalize()?;
+    Ok(())
+}
+
+pub fn render_loop_to_wav<P: AsRef<Path>>(
+    sound: Sound,
+    output: P,
+    settings: RenderSettings,
+) -> anyhow::Result<()> {
+    let frames = render_loop(sound, settings)?;
+    write_wav(output, &frames, settings.sample_rate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::SMALLEST_LOOP_UPPER_BOUND;
+
+    const SAMPLE_RATE: u32 = 1000;
+
+    // One second rising from 0 to 1, so every frame tells where in the sound it came from.
+    fn ramp() -> Sound {
+        let frames: Vec<Frame> = (0..SAMPLE_RATE)
+            .map(|i| {
+                let value = i as f32 / SAMPLE_RATE as f32;
+                Frame {
+                    left: value,
+                    right: value,
+                }
+            })
+            .collect();
+        Sound::from_frames(SAMPLE_RATE, &frames)
+    }
+
+    fn render(loop_start: f64, loop_length: f64, pitch: f64) -> Vec<f32> {
+        let settings = RenderSettings {
+            loop_start,
+            loop_length,
+            pitch,
+            seconds: 0.5,
+            sample_rate: SAMPLE_RATE,
+        };
+        render_loop(ramp(), settings)
+            .unwrap()
+            .iter()
+            .map(|frame| frame.left)
+            .collect()
+    }
+
+    // Index of the first frame that is lower than the one before, where the loop came around.
+    fn first_wrap(frames: &[f32]) -> usize {
+        frames
+            .windows(2)
+            .position(|pair| pair[1] < pair[0])
+            .map(|index| index + 1)
+            .unwrap()
+    }
+
+    #[test]
+    fn wraps_at_the_loop_end() {
+        let frames = render(0.2, 0.1, 1.0);
+        let wrap = first_wrap(&frames);
+        assert!((99..=102).contains(&wrap), "wrapped at {}", wrap);
+        // Back to where the loop started, and never outside of it.
+        assert!((frames[wrap] - frames[0]).abs() <= frames[0] * 0.02);
+        let highest = frames.iter().copied().fold(f32::MIN, f32::max);
+        assert!(highest <= frames[0] * 1.52);
+        assert!(frames.iter().all(|&value| value >= frames[0] * 0.99));
+    }
+
+    #[test]
+    fn pitch_scales_the_playback_rate() {
+        let normal = render(0.2, 0.1, 1.0);
+        let doubled = render(0.2, 0.1, 2.0);
+        let normal_step = normal[11] - normal[10];
+        let doubled_step = doubled[11] - doubled[10];
+        assert!((doubled_step / normal_step - 2.0).abs() < 0.01);
+        let wrap = first_wrap(&doubled);
+        assert!((49..=52).contains(&wrap), "wrapped at {}", wrap);
+    }
+
+    #[test]
+    fn empty_loops_play_the_smallest_loop() {
+        assert_eq!(
+            loop_bounds(0.5, 0.0, 1.0),
+            Some((0.5, 0.5 + SMALLEST_LOOP_UPPER_BOUND))
+        );
+        let frames = render(0.5, 0.0, 1.0);
+        let smallest_frames = (SMALLEST_LOOP_UPPER_BOUND * SAMPLE_RATE as f64) as usize;
+        let wrap = first_wrap(&frames);
+        assert!(wrap.abs_diff(smallest_frames) <= 2, "wrapped at {}", wrap);
+        let highest = frames.iter().copied().fold(f32::MIN, f32::max);
+        assert!(highest <= frames[0] * (1.0 + (smallest_frames + 2) as f32 / 500.0));
+    }
+
+    #[test]
+    fn loops_past_the_end_are_rejected() {
+        let settings = RenderSettings {
+            loop_start: 1.5,
+            loop_length: 0.1,
+            pitch: 1.0,
+            seconds: 0.1,
+            sample_rate: SAMPLE_RATE,
+        };
+        assert!(render_loop(ramp(), settings).is_err());
+    }
+}
@@ -1,22 +1,61 @@
-use std::sync::Arc;
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 use crate::{
-    decoder::{compress_samples, decode_wav, DecodedSamples},
+    decoder::{
+        decode_file_with_progress, deinterleave, mix_down, DecodeMode, DecodeProgress,
+        DecodedAudio, PeakBin, PeakPyramid,
+    },
+    engine::{EngineCommand, EngineState, PunchState, TrackState, RESAMPLE_SAMPLE_RATE},
+    peak_cache,
+    recorder::{
+        input_channel_count, CaptureMode, ChannelMode, InputRouting, RecordTrigger, Recorder,
+        Recording, DEFAULT_CAPTURE_SECONDS, DEFAULT_PHRASE_THRESHOLD,
+    },
+    render::write_wav,
     svg_map::{Icon, IconName},
-    utils::{calculate_spacing, scale_values_to_unit_range},
+    utils::scale_value,
 };
-use crossbeam::channel::Sender;
+use crossbeam::channel::{bounded, Receiver, Sender};
 use gpui::{
-    div, hsla, overlay, point, prelude::FluentBuilder, px, size, AnchorCorner, Bounds, BoxShadow,
+    canvas, div, hsla, overlay, point, prelude::FluentBuilder, px, size, Bounds, BoxShadow, Div,
     ExternalPaths, GlobalPixels, InteractiveElement, IntoElement, Model, ModelContext,
-    ParentElement, Pixels, Point, Render, ScrollDelta, ScrollWheelEvent, SharedString, Styled,
-    TitlebarOptions, ViewContext, WindowBounds, WindowKind, WindowOptions,
+    MouseDownEvent, ParentElement, Pixels, Point, Render, ScrollDelta, ScrollWheelEvent,
+    SharedString, Styled, Task, TitlebarOptions, ViewContext, WindowBounds, WindowContext,
+    WindowKind, WindowOptions,
 };
-use kittyaudio::Sound;
 use smallvec::smallvec;
 
-const MAX_NUMBER_OF_SAMPLES_SHOWN: i32 = 160;
-const WAVEFORM_SAMPLES_PIXELS: f32 = 5.0;
+const WAVEFORM_BIN_PIXELS: f32 = 2.0;
+// How far the glow around the waveform reaches.
+const WAVEFORM_SHADOW_SPREAD: f32 = 2.0;
+const ENGINE_STATE_REFRESH: Duration = Duration::from_millis(16);
+const RECORDER_REFRESH: Duration = Duration::from_millis(20);
+const LOADING_REFRESH: Duration = Duration::from_millis(50);
+const RESAMPLE_DIR: &str = "resamples";
+pub const TRACK_COUNT: usize = 4;
+const LANES_TOP: f32 = 40.0;
+const LANE_HEIGHT: f32 = 180.0;
+const LANE_WIDTH: f32 = 800.0;
+const SQUARE_HEIGHT: f32 = 96.0;
+const WAVEFORM_AMPLITUDE: f32 = 75.0;
+// Shortest stretch of a sound the view zooms in to.
+const MIN_VIEW_SECONDS: f64 = 0.25;
+// Scrolling this far zooms in or out twice as much.
+const ZOOM_PIXELS_PER_DOUBLING: f64 = 100.0;
+// Ruler ticks are spaced by the first of these that leaves room for their labels.
+const RULER_STEPS: [f64; 18] = [
+    0.001, 0.002, 0.005, 0.01, 0.02, 0.05, 0.1, 0.2, 0.5, 1.0, 2.0, 5.0, 10.0, 30.0, 60.0, 120.0,
+    300.0, 600.0,
+];
+const RULER_MIN_TICK_PIXELS: f64 = 80.0;
+const READOUT_WIDTH: f32 = 300.0;
+const TEMPOS: [f64; 6] = [90.0, 100.0, 110.0, 120.0, 130.0, 140.0];
+const BEATS_PER_BAR: f64 = 4.0;
 
 // Colors
 const BG: (f32, f32, f32, f32) = (0.0, 0.0, 0.76, 1.0);
@@ -26,87 +65,421 @@ const WAVEFORM_LOWER: (f32, f32, f32, f32) = (0.61, 0.76, 0.76, 0.4);
 const WAVEFORM_LOWER_SH: (f32, f32, f32, f32) = (0.3, 0.2, 0.74, 0.3);
 const SQUARE: (f32, f32, f32, f32) = (0.0, 0.45, 0.57, 0.5);
 const SQUARE_SH: (f32, f32, f32, f32) = (0.0, 0.85, 0.74, 0.9);
+const PLAYHEAD: (f32, f32, f32, f32) = (0.0, 0.0, 1.0, 0.9);
+const LANE_SELECTED: (f32, f32, f32, f32) = (0.0, 0.0, 1.0, 0.15);
 const HELP_BG: (f32, f32, f32, f32) = (0.14, 0.43, 0.83, 0.2);
 const HELP_TEXT: (f32, f32, f32, f32) = (0.58, 0.14, 0.55, 0.9);
 const HELP_SH: (f32, f32, f32, f32) = (0.575, 0.45, 0.84, 0.4);
 const HELP_IC: (f32, f32, f32, f32) = (0.2, 0.244, 0.89, 0.5);
+const RECORD_IC: (f32, f32, f32, f32) = (0.0, 0.5, 0.6, 0.5);
+const RECORD_IC_ACTIVE: (f32, f32, f32, f32) = (0.0, 0.85, 0.55, 1.0);
+const RECORD_IC_ARMED: (f32, f32, f32, f32) = (0.0, 0.7, 0.75, 0.8);
+const CAPTURE_IC: (f32, f32, f32, f32) = (0.4, 0.5, 0.55, 0.6);
+const OVERDUB_IC: (f32, f32, f32, f32) = (0.08, 0.5, 0.6, 0.5);
+const OVERDUB_IC_ACTIVE: (f32, f32, f32, f32) = (0.08, 0.9, 0.55, 1.0);
+const PUNCH_IC: (f32, f32, f32, f32) = (0.8, 0.5, 0.6, 0.5);
+const PUNCH_IC_ACTIVE: (f32, f32, f32, f32) = (0.8, 0.8, 0.6, 1.0);
+const RESAMPLE_IC: (f32, f32, f32, f32) = (0.5, 0.5, 0.6, 0.5);
+const RESAMPLE_IC_ACTIVE: (f32, f32, f32, f32) = (0.5, 0.85, 0.55, 1.0);
+const TRANSPORT_IC: (f32, f32, f32, f32) = (0.0, 0.0, 0.95, 0.9);
+const TRANSPORT_IC_PAUSED: (f32, f32, f32, f32) = (0.0, 0.0, 0.95, 0.4);
+const PUNCH_REGION: (f32, f32, f32, f32) = (0.8, 0.6, 0.7, 0.35);
+const TAKE_TEXT: (f32, f32, f32, f32) = (0.58, 0.14, 0.55, 0.9);
+const WAVEFORM_ERROR: (f32, f32, f32, f32) = (0.0, 0.8, 0.6, 0.9);
+const LOADING: (f32, f32, f32, f32) = (0.58, 0.5, 0.7, 0.9);
+const ROUTING_TEXT: (f32, f32, f32, f32) = (0.58, 0.14, 0.55, 0.9);
+const RECORDER_WARNING: (f32, f32, f32, f32) = (0.0, 0.8, 0.6, 0.9);
+const FEEDBACK: (f32, f32, f32, f32) = (0.08, 0.9, 0.7, 0.8);
+const RULER: (f32, f32, f32, f32) = (0.58, 0.14, 0.55, 0.6);
+const READOUT_TEXT: (f32, f32, f32, f32) = (0.58, 0.14, 0.55, 0.9);
+
+pub struct Track {
+    pub loop_model: Model<Loop>,
+    pub waveform_model: Model<WaveformModel>,
+}
 
 pub struct Root {
-    loop_model: Model<Loop>,
+    tracks: Vec<Track>,
+    selected_track: usize,
     help_model: Model<Help>,
-    waveform_model: Model<WaveformModel>,
-    sound_sender: Sender<Sound>,
+    engine_state_model: Model<EngineStateModel>,
+    engine_sender: Sender<EngineCommand>,
+    recorder: Recorder,
+    recorder_task: Option<Task<()>>,
+    overdub_track: Option<usize>,
+    // Track being punched into, with its punch count when the punch was armed.
+    punch_track: Option<(usize, usize)>,
+    record_trigger: RecordTrigger,
+    count_in_task: Option<Task<()>>,
+    // Beats left before a counted-in take starts.
+    count_in_beats_left: Option<u32>,
+    resampling: bool,
+    resample_task: Option<Task<()>>,
+    waveform_view: WaveformView,
+    // Bars and beats are only shown once a tempo is picked.
+    tempo: Option<f64>,
 }
 
 impl Root {
     pub fn new(
-        loop_model: Model<Loop>,
+        tracks: Vec<Track>,
         help_model: Model<Help>,
-        waveform_model: Model<WaveformModel>,
+        engine_state_model: Model<EngineStateModel>,
         cx: &mut ViewContext<Self>,
-        sound_sender: Sender<Sound>,
+        engine_sender: Sender<EngineCommand>,
     ) -> Self {
-        cx.observe(&loop_model, |_, _, cx| cx.notify()).detach();
+        for track in &tracks {
+            cx.observe(&track.loop_model, |_, _, cx| cx.notify())
+                .detach();
+            cx.observe(&track.waveform_model, |_, _, cx| cx.notify())
+                .detach();
+        }
         cx.observe(&help_model, |_, _, cx| cx.notify()).detach();
+        // The input stays open so that takes can start instantly and the past can be captured.
+        let mut recorder = Recorder::default();
+        if let Err(e) = recorder.open() {
+            eprintln!("Error opening input: {:?}", e);
+        }
+        cx.observe(&engine_state_model, |_, _, cx| cx.notify())
+            .detach();
         Self {
-            loop_model,
+            tracks,
+            selected_track: 0,
             help_model,
-            waveform_model,
-            sound_sender,
+            engine_state_model,
+            engine_sender,
+            recorder,
+            recorder_task: None,
+            overdub_track: None,
+            punch_track: None,
+            record_trigger: RecordTrigger::default(),
+            count_in_task: None,
+            count_in_beats_left: None,
+            resampling: false,
+            resample_task: None,
+            waveform_view: WaveformView::default(),
+            tempo: None,
         }
     }
 }
 
-pub struct WaveformModel {
-    path: SharedString,
-    samples: Option<Arc<Vec<f32>>>,
+pub struct EngineStateModel {
+    pub state: EngineState,
 }
 
-impl WaveformModel {
-    pub fn new(path: SharedString, cx: &mut ModelContext<Self>) -> Self {
-        // TODO Make async
-        let mut new_samples = Some(Arc::new(vec![0.0]));
-        match decode_wav(path.to_string()) {
-            Ok(DecodedSamples::F32(samples)) => {
-                let compressed_samples = compress_samples(&samples, 160);
-                let scaled_samples = Arc::new(scale_values_to_unit_range(compressed_samples));
-
-                new_samples = Some(scaled_samples);
+impl EngineStateModel {
+    pub fn new(receiver: Receiver<EngineState>, cx: &mut ModelContext<Self>) -> Self {
+        cx.spawn(|this, mut cx| async move {
+            loop {
+                cx.background_executor().timer(ENGINE_STATE_REFRESH).await;
+                let Some(state) = receiver.try_iter().last() else {
+                    continue;
+                };
+                let updated = this.update(&mut cx, |model, cx| {
+                    model.state = state;
+                    cx.notify();
+                });
+                if updated.is_err() {
+                    break;
+                }
             }
-            Ok(DecodedSamples::I16(samples)) => {
-                let compressed_samples = compress_samples(&samples, 160);
-                let scaled_samples = Arc::new(scale_values_to_unit_range(compressed_samples));
-                new_samples = Some(scaled_samples);
+        })
+        .detach();
+
+        Self {
+            state: EngineState::default(),
+        }
+    }
+
+    // Fraction of the applied loop the playhead has travelled through.
+    pub fn loop_progress(&self, track: usize) -> f32 {
+        let Some(state) = self.state.tracks.get(track) else {
+            return 0.0;
+        };
+        let loop_length = state.loop_end - state.loop_start;
+        if loop_length <= 0.0 {
+            return 0.0;
+        }
+        ((state.playhead - state.loop_start) / loop_length).clamp(0.0, 1.0) as f32
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum WaveformView {
+    // All channels averaged into one trace.
+    #[default]
+    Mixed,
+    // One lane per channel.
+    Channels,
+    // Sum and difference of the first two channels.
+    MidSide,
+}
+
+impl WaveformView {
+    pub fn next(self) -> Self {
+        match self {
+            WaveformView::Mixed => WaveformView::Channels,
+            WaveformView::Channels => WaveformView::MidSide,
+            WaveformView::MidSide => WaveformView::Mixed,
+        }
+    }
+}
+
+impl fmt::Display for WaveformView {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WaveformView::Mixed => write!(f, "MIX"),
+            WaveformView::Channels => write!(f, "CHANNELS"),
+            WaveformView::MidSide => write!(f, "MID/SIDE"),
+        }
+    }
+}
+
+// Peak pyramids for every view, worked out once per sound.
+struct WaveformSummary {
+    mixed: Vec<PeakPyramid>,
+    channels: Vec<PeakPyramid>,
+    mid_side: Vec<PeakPyramid>,
+}
+
+impl WaveformSummary {
+    fn new(samples: &[f32], channels: u16) -> Self {
+        let separated = deinterleave(samples, channels);
+        let mid_side = match separated.as_slice() {
+            [left, right, ..] => {
+                let mid: Vec<f32> = left.iter().zip(right).map(|(l, r)| (l + r) * 0.5).collect();
+                let side: Vec<f32> = left.iter().zip(right).map(|(l, r)| (l - r) * 0.5).collect();
+                vec![PeakPyramid::new(&mid), PeakPyramid::new(&side)]
             }
-            Err(e) => eprintln!("Error decoding WAV file: {:?}", e),
+            _ => Vec::new(),
+        };
+        Self {
+            mixed: vec![PeakPyramid::new(&mix_down(samples, channels))],
+            channels: separated
+                .iter()
+                .map(|channel| PeakPyramid::new(channel))
+                .collect(),
+            mid_side,
         }
-        cx.notify();
+    }
+
+    // The pyramids stored the last time `path` was loaded, if it hasn't changed since.
+    fn cached(path: &str) -> Option<Self> {
+        let [mixed, channels, mid_side]: [Vec<PeakPyramid>; 3] =
+            peak_cache::load(Path::new(path))?.try_into().ok()?;
+        Some(Self {
+            mixed,
+            channels,
+            mid_side,
+        })
+    }
+
+    fn store(&self, path: &str) {
+        let groups = [
+            self.mixed.as_slice(),
+            self.channels.as_slice(),
+            self.mid_side.as_slice(),
+        ];
+        if let Err(e) = peak_cache::store(Path::new(path), &groups) {
+            eprintln!("Failed caching the waveform of {}: {:#}", path, e);
+        }
+    }
+}
+
+// Decodes a file and builds its pyramids unless they were already cached, both too slow for
+// the UI thread. Only fully decoded files are cached, so a damaged one is reported every time.
+fn decode_waveform(
+    path: String,
+    progress: &DecodeProgress,
+    cached: Option<Arc<WaveformSummary>>,
+) -> anyhow::Result<(DecodedAudio, Arc<WaveformSummary>)> {
+    let audio = decode_file_with_progress(&path, DecodeMode::Partial, progress)?;
+    let summary = match cached {
+        Some(summary) => summary,
+        None => {
+            let summary = WaveformSummary::new(&audio.samples, audio.channels);
+            if audio.error.is_none() {
+                summary.store(&path);
+            }
+            Arc::new(summary)
+        }
+    };
+    Ok((audio, summary))
+}
+
+fn cached_waveform(path: String) -> Option<Arc<WaveformSummary>> {
+    WaveformSummary::cached(&path).map(Arc::new)
+}
+
+pub struct WaveformModel {
+    path: SharedString,
+    summary: Option<Arc<WaveformSummary>>,
+    // Why the file could not be shown, or was only partly decoded.
+    error: Option<SharedString>,
+    // Set while a file is being decoded in the background.
+    loading: Option<Arc<DecodeProgress>>,
+    // Redraws the progress while loading.
+    loading_task: Option<Task<()>>,
+}
 
+impl WaveformModel {
+    // A waveform for audio that is already decoded, like the sound the app starts with.
+    pub fn from_audio(audio: &DecodedAudio) -> Self {
+        let mut waveform = Self::empty();
+        waveform.summary = Some(Arc::new(WaveformSummary::new(
+            &audio.samples,
+            audio.channels,
+        )));
+        waveform
+    }
+
+    pub fn empty() -> Self {
         Self {
-            path,
-            samples: new_samples,
+            path: SharedString::from(""),
+            summary: None,
+            error: None,
+            loading: None,
+            loading_task: None,
         }
     }
 
-    pub fn update_samples(&mut self, path: SharedString, cx: &mut ModelContext<Self>) {
+    // Cancels whatever was loading and shows the progress of a new decode of `path` instead.
+    pub fn start_loading(
+        &mut self,
+        path: SharedString,
+        cx: &mut ModelContext<Self>,
+    ) -> Arc<DecodeProgress> {
+        self.cancel_loading();
+        let progress = Arc::new(DecodeProgress::default());
         self.path = path;
-        // TODO Make async
-        match decode_wav(self.path.to_string()) {
-            Ok(DecodedSamples::F32(samples)) => {
-                let compressed_samples = compress_samples(&samples, 160);
-                let scaled_samples = Arc::new(scale_values_to_unit_range(compressed_samples));
+        self.summary = None;
+        self.error = None;
+        self.loading = Some(progress.clone());
+        self.loading_task = Some(cx.spawn(|this, mut cx| async move {
+            loop {
+                cx.background_executor().timer(LOADING_REFRESH).await;
+                let loading = this.update(&mut cx, |waveform, cx| {
+                    cx.notify();
+                    waveform.loading.is_some()
+                });
+                if !loading.unwrap_or(false) {
+                    break;
+                }
+            }
+        }));
+        cx.notify();
+        progress
+    }
 
-                self.samples = Some(scaled_samples);
+    // Shows a cached waveform while the file it belongs to is still being decoded.
+    fn show_cached(
+        &mut self,
+        progress: &Arc<DecodeProgress>,
+        summary: Arc<WaveformSummary>,
+        cx: &mut ModelContext<Self>,
+    ) {
+        if self.is_loading(progress) {
+            self.summary = Some(summary);
+            cx.notify();
+        }
+    }
+
+    // Shows a finished decode, unless a newer one took its place meanwhile.
+    fn finish_loading(
+        &mut self,
+        progress: &Arc<DecodeProgress>,
+        result: anyhow::Result<(DecodedAudio, Arc<WaveformSummary>)>,
+        cx: &mut ModelContext<Self>,
+    ) -> Option<DecodedAudio> {
+        if !self.is_loading(progress) {
+            return None;
+        }
+        self.loading = None;
+        match result {
+            Ok((audio, summary)) => {
+                self.show_audio(&audio, summary, cx);
+                Some(audio)
             }
-            Ok(DecodedSamples::I16(samples)) => {
-                let compressed_samples = compress_samples(&samples, 160);
-                let scaled_samples = Arc::new(scale_values_to_unit_range(compressed_samples));
-                self.samples = Some(scaled_samples);
+            Err(e) => {
+                self.summary = None;
+                self.error = Some(SharedString::from(format!("{:#}", e)));
+                cx.notify();
+                None
             }
-            Err(e) => eprintln!("Error decoding WAV file: {:?}", e),
         }
+    }
+
+    fn is_loading(&self, progress: &Arc<DecodeProgress>) -> bool {
+        self.loading
+            .as_ref()
+            .is_some_and(|loading| Arc::ptr_eq(loading, progress))
+    }
+
+    fn cancel_loading(&mut self) {
+        if let Some(progress) = self.loading.take() {
+            progress.cancel();
+        }
+    }
+
+    pub fn loading_fraction(&self) -> Option<f32> {
+        self.loading.as_ref().map(|progress| progress.fraction())
+    }
+
+    // Name of the file shown or being loaded, without its directories.
+    pub fn file_name(&self) -> String {
+        Path::new(&self.path.to_string())
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    }
+
+    fn show_audio(
+        &mut self,
+        audio: &DecodedAudio,
+        summary: Arc<WaveformSummary>,
+        cx: &mut ModelContext<Self>,
+    ) {
+        self.summary = Some(summary);
+        self.error = audio
+            .error
+            .as_ref()
+            .map(|error| SharedString::from(format!("PARTIAL: {:#}", error)));
         cx.notify()
     }
+
+    pub fn update_from_recording(&mut self, recording: &Recording, cx: &mut ModelContext<Self>) {
+        self.cancel_loading();
+        self.path = SharedString::from("");
+        self.summary = Some(Arc::new(WaveformSummary::new(
+            &recording.samples,
+            recording.channels,
+        )));
+        self.error = None;
+        cx.notify()
+    }
+
+    // The traces to draw for `view`, `width` bins each between `start` and `end` of the sound,
+    // mono sounds showing their one channel for mid/side.
+    pub fn lanes(
+        &self,
+        view: WaveformView,
+        start: f64,
+        end: f64,
+        width: usize,
+    ) -> Vec<Vec<PeakBin>> {
+        let Some(summary) = &self.summary else {
+            return Vec::new();
+        };
+        let pyramids = match view {
+            WaveformView::Mixed => &summary.mixed,
+            WaveformView::MidSide if !summary.mid_side.is_empty() => &summary.mid_side,
+            WaveformView::Channels | WaveformView::MidSide => &summary.channels,
+        };
+        pyramids
+            .iter()
+            .map(|pyramid| pyramid.bins(start, end, width))
+            .collect()
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -120,7 +493,7 @@ impl Help {
         self.is_shown = !self.is_shown;
         if self.is_shown {
             self.text =
-                SharedString::from("      [ H-SCROLL ]\n       loop position\n[ CTRL+V-SCROLL ]\n         loop size\n  [ ALT+V-SCROLL ]\n    pitch adjustment");
+                SharedString::from("      [ H-SCROLL ]\n       loop position\n[ CTRL+V-SCROLL ]\n         loop size\n  [ CMD+V-SCROLL ]\n    pitch adjustment\n        [ CLICK ]\n      select track\n      [ RED DOT ]\n   record into track\n  [ ORANGE DOT ]\n  overdub onto track\n  [ PURPLE DOT ]\n    punch into loop\n[ SHIFT+SCROLL ]\n     punch region\n  [ TAKE LABEL ]\n       switch take\n     [ BLUE DOT ]\n  resample output\n[ CTRL+BLUE DOT ]\n  resample and loop\n    [ WHITE DOT ]\n   pause all tracks\n     [ MIX LABEL ]\n     waveform view\n     [ BPM LABEL ]\n   tempo for bars\n[ FREE/LEVEL/COUNT ]\n      record trigger\n [ CTRL+TRIGGER ]\n    level or beats\n    [ GREEN DOT ]\n  capture last phrase\n[ CTRL+GREEN DOT ]\n capture last 8 sec\n   [ ALT+V-SCROLL ]\n  overdub feedback\n[ CTRL+ALT+V-SCROLL ]\n        zoom view\n[ CTRL+ALT+H-SCROLL ]\n       scroll view");
         } else {
             self.text = SharedString::from("");
         }
@@ -135,15 +508,17 @@ impl Render for Help {
 
 #[derive(Debug)]
 pub struct Loop {
+    pub track: usize,
     pub loop_position: Pixels,
     pub square_height: Pixels,
     pub square_width: Pixels,
-    pub sender: Sender<(
-        Option<Pixels>,
-        Option<Pixels>,
-        Option<Pixels>,
-        Option<GlobalPixels>,
-    )>,
+    pub sound_duration: f64,
+    pub overdub_feedback: f32,
+    // Punch-in and punch-out points as fractions of the loop.
+    pub punch: (f32, f32),
+    // The part of the sound spread over the window, as fractions of it.
+    pub view: (f64, f64),
+    pub sender: Sender<EngineCommand>,
 }
 
 impl Loop {
@@ -155,21 +530,818 @@ impl Loop {
         square_width: Pixels,
         windows_width: GlobalPixels,
     ) {
+        // The square lives in pixels of the whole sound, so moves on screen shrink as the view zooms in.
+        let view_span = self.view_span() as f32;
         if let Some(x) = loop_position {
-            self.loop_position += x;
+            self.loop_position += x * view_span;
         } else {
             self.loop_position = self.loop_position;
         }
-        self.square_width += square_width;
+        self.square_width += square_width * view_span;
         self.square_height += square_height;
-        let _s = self.sender.send((
-            Some(self.loop_position),
-            Some(self.square_height.clamp(px(0.0), px(360.0))),
-            Some(self.square_width),
-            Some(windows_width),
-        ));
+
+        let (start, length) = self.loop_seconds(windows_width);
+        let pitch = scale_value(
+            self.square_height.clamp(px(0.0), px(360.0)).0 as f64,
+            (0.0, 1024.0),
+            (0.0, 8.0),
+        );
+        let _s = self.sender.send(EngineCommand::SetLoopSeconds {
+            track: self.track,
+            start,
+            length,
+        });
+        let _s = self.sender.send(EngineCommand::SetPitch {
+            track: self.track,
+            factor: pitch,
+        });
+        self.send_punch(windows_width);
+        cx.notify();
+    }
+
+    // Loop start and length in seconds, from the square's place on screen.
+    fn loop_seconds(&self, windows_width: GlobalPixels) -> (f64, f64) {
+        let window_width: f64 = windows_width.into();
+        let start = scale_value(
+            self.loop_position.0 as f64,
+            (0.0, window_width),
+            (0.0, self.sound_duration),
+        );
+        let length = scale_value(
+            self.square_width.0 as f64,
+            (0.0, window_width),
+            (0.0, self.sound_duration),
+        );
+        (start, length)
+    }
+
+    pub fn change_punch(
+        &mut self,
+        cx: &mut ModelContext<Self>,
+        shift: Pixels,
+        width: Pixels,
+        windows_width: GlobalPixels,
+    ) {
+        let square_width = self.square_width.max(1.0.into()).0 / self.view_span() as f32;
+        let punch_width = (self.punch.1 - self.punch.0 + width.0 / square_width).clamp(0.01, 1.0);
+        let punch_in = (self.punch.0 + shift.0 / square_width).clamp(0.0, 1.0 - punch_width);
+        self.punch = (punch_in, punch_in + punch_width);
+        self.send_punch(windows_width);
+        cx.notify();
+    }
+
+    fn send_punch(&self, windows_width: GlobalPixels) {
+        let (start, length) = self.loop_seconds(windows_width);
+        let _s = self.sender.send(EngineCommand::SetPunch {
+            track: self.track,
+            start: start + length * self.punch.0 as f64,
+            end: start + length * self.punch.1 as f64,
+        });
+    }
+
+    pub fn change_feedback(&mut self, cx: &mut ModelContext<Self>, feedback: Pixels) {
+        self.overdub_feedback = (self.overdub_feedback + feedback.0 / 360.0).clamp(0.0, 1.0);
+        let _s = self.sender.send(EngineCommand::SetOverdubFeedback {
+            track: self.track,
+            feedback: self.overdub_feedback,
+        });
+        cx.notify();
+    }
+
+    // Spans the square over the first `length` seconds and loops exactly that.
+    pub fn fit_loop(
+        &mut self,
+        cx: &mut ModelContext<Self>,
+        length: f64,
+        windows_width: GlobalPixels,
+    ) {
+        let window_width: f64 = windows_width.into();
+        self.loop_position = px(0.0);
+        self.square_width =
+            px(scale_value(length, (0.0, self.sound_duration), (0.0, window_width)) as f32);
+        let _s = self.sender.send(EngineCommand::SetLoopSeconds {
+            track: self.track,
+            start: 0.0,
+            length,
+        });
+        self.send_punch(windows_width);
+        cx.notify();
+    }
+
+    pub fn set_sound_duration(&mut self, sound_duration: f64) {
+        self.sound_duration = sound_duration;
+        self.view = (0.0, 1.0);
+    }
+
+    // Zooms in for positive `amount`, keeping the part of the sound under `x` in place.
+    pub fn zoom(
+        &mut self,
+        cx: &mut ModelContext<Self>,
+        amount: Pixels,
+        x: Pixels,
+        windows_width: GlobalPixels,
+    ) {
+        let window_width: f64 = windows_width.into();
+        let anchor = (x.0 as f64 / window_width).clamp(0.0, 1.0);
+        let min_span = (MIN_VIEW_SECONDS / self.sound_duration).min(1.0);
+        let span = (self.view_span() * 2f64.powf(-amount.0 as f64 / ZOOM_PIXELS_PER_DOUBLING))
+            .clamp(min_span, 1.0);
+        let start =
+            (self.view.0 + self.view_span() * anchor - span * anchor).clamp(0.0, 1.0 - span);
+        self.view = (start, start + span);
+        cx.notify();
+    }
+
+    pub fn scroll_view(
+        &mut self,
+        cx: &mut ModelContext<Self>,
+        shift: Pixels,
+        windows_width: GlobalPixels,
+    ) {
+        let window_width: f64 = windows_width.into();
+        let span = self.view_span();
+        let start = (self.view.0 + shift.0 as f64 / window_width * span).clamp(0.0, 1.0 - span);
+        self.view = (start, start + span);
+        cx.notify();
+    }
+
+    pub fn view_span(&self) -> f64 {
+        self.view.1 - self.view.0
+    }
+
+    // Where `x`, in pixels of the whole sound, is drawn in the zoomed view.
+    pub fn screen_x(&self, x: Pixels, window_width: f64) -> Pixels {
+        px(((x.0 as f64 - self.view.0 * window_width) / self.view_span()) as f32)
+    }
+
+    pub fn screen_width(&self, width: Pixels) -> Pixels {
+        px((width.0 as f64 / self.view_span()) as f32)
+    }
+
+    // Where `seconds` into the sound is drawn in the zoomed view.
+    pub fn seconds_x(&self, seconds: f64, window_width: f64) -> Pixels {
+        px(
+            ((seconds / self.sound_duration - self.view.0) / self.view_span() * window_width)
+                as f32,
+        )
+    }
+
+    // Seconds of the sound at the left and right edges of the window.
+    pub fn visible_seconds(&self) -> (f64, f64) {
+        (
+            self.view.0 * self.sound_duration,
+            self.view.1 * self.sound_duration,
+        )
+    }
+}
+
+impl Root {
+    fn toggle_recording(&mut self, cx: &mut ViewContext<Self>) {
+        if self.overdub_track.is_some() || self.punch_track.is_some() {
+            return;
+        }
+        // Clicking again during the count-in calls it off.
+        if self.count_in_beats_left.is_some() {
+            self.count_in_task = None;
+            self.count_in_beats_left = None;
+            cx.notify();
+            return;
+        }
+        if !self.recorder.is_recording() && !self.recorder.is_armed() {
+            match self.record_trigger {
+                RecordTrigger::Immediate => self.start_recorder(None, cx),
+                RecordTrigger::Level(threshold) => self.arm_recorder(threshold, cx),
+                RecordTrigger::CountIn { beats, bpm } => self.start_count_in(beats, bpm, cx),
+            }
+            return;
+        }
+        match self.stop_recorder() {
+            Some(recording) => self.load_recording(recording, cx),
+            None => cx.notify(),
+        }
+    }
+
+    fn toggle_overdub(&mut self, cx: &mut ViewContext<Self>) {
+        if self.punch_track.is_some() {
+            return;
+        }
+        if let Some(track) = self.overdub_track {
+            if let Some(recording) = self.stop_recorder() {
+                self.send_overdub(track, recording);
+            }
+            cx.notify();
+            return;
+        }
+        if self.recorder.is_recording()
+            || self.recorder.is_armed()
+            || self.count_in_beats_left.is_some()
+        {
+            return;
+        }
+        let _s = self.engine_sender.send(EngineCommand::StartOverdub {
+            track: self.selected_track,
+        });
+        self.start_recorder(Some(self.selected_track), cx);
+    }
+
+    fn toggle_punch(&mut self, cx: &mut ViewContext<Self>) {
+        if let Some((track, _)) = self.punch_track {
+            let _s = self
+                .engine_sender
+                .send(EngineCommand::CancelPunch { track });
+            self.stop_recorder();
+            cx.notify();
+            return;
+        }
+        if self.recorder.is_recording()
+            || self.recorder.is_armed()
+            || self.count_in_beats_left.is_some()
+        {
+            return;
+        }
+        let track = self.selected_track;
+        let punch_count = self
+            .engine_state_model
+            .read(cx)
+            .state
+            .tracks
+            .get(track)
+            .map_or(0, |state| state.punch_count);
+        let window_width = window_width(cx);
+        self.tracks[track]
+            .loop_model
+            .update(cx, |square, _cx| square.send_punch(window_width));
+        let _s = self.engine_sender.send(EngineCommand::ArmPunch { track });
+        if let Err(e) = self.recorder.start() {
+            eprintln!("Error starting recording: {:?}", e);
+            let _s = self
+                .engine_sender
+                .send(EngineCommand::CancelPunch { track });
+            return;
+        }
+        self.punch_track = Some((track, punch_count));
+        self.watch_recorder(cx);
+    }
+
+    // Tapes the engine output; stopping writes it to a new WAV and, if asked, loops it on the selected track.
+    fn toggle_resample(&mut self, load: bool, cx: &mut ViewContext<Self>) {
+        if !self.resampling {
+            let _s = self.engine_sender.send(EngineCommand::StartResample);
+            self.resampling = true;
+            cx.notify();
+            return;
+        }
+        self.resampling = false;
+        let (reply, frames_receiver) = bounded(1);
+        let _s = self
+            .engine_sender
+            .send(EngineCommand::StopResample { reply });
+        let path = resample_path();
+        self.resample_task = Some(cx.spawn(|this, mut cx| async move {
+            let frames = cx
+                .background_executor()
+                .spawn(async move {
+                    let frames = frames_receiver.recv().ok()?;
+                    if let Err(e) = std::fs::create_dir_all(RESAMPLE_DIR)
+                        .map_err(anyhow::Error::from)
+                        .and_then(|_| write_wav(&path, &frames, RESAMPLE_SAMPLE_RATE))
+                    {
+                        eprintln!("Error writing resample: {:?}", e);
+                    } else {
+                        println!("Resampled to {}", path.display());
+                    }
+                    Some(frames)
+                })
+                .await;
+            let Some(frames) = frames else {
+                return;
+            };
+            if load && !frames.is_empty() {
+                let _s = this.update(&mut cx, |root, cx| {
+                    root.load_recording(Recording::from_frames(RESAMPLE_SAMPLE_RATE, &frames), cx);
+                });
+            }
+        }));
+        cx.notify();
+    }
+
+    // Whether any track is playing, as the engine last reported.
+    fn is_playing(&self, cx: &mut ViewContext<Self>) -> bool {
+        self.engine_state_model
+            .read(cx)
+            .state
+            .tracks
+            .iter()
+            .any(|state| state.is_playing)
+    }
+
+    // Pauses or resumes every track at once, keeping their places in the loops.
+    fn toggle_pause(&mut self, cx: &mut ViewContext<Self>) {
+        let _s = self.engine_sender.send(match self.is_playing(cx) {
+            true => EngineCommand::Pause,
+            false => EngineCommand::Resume,
+        });
+        cx.notify();
+    }
+
+    fn cycle_take(&mut self, track: usize, cx: &mut ViewContext<Self>) {
+        // The engine keeps the take a punch started from until the punch ends.
+        if self
+            .punch_track
+            .is_some_and(|(punch_track, _)| punch_track == track)
+        {
+            return;
+        }
+        let Some(state) = self.engine_state_model.read(cx).state.tracks.get(track) else {
+            return;
+        };
+        if state.take_count == 0 {
+            return;
+        }
+        let _s = self.engine_sender.send(EngineCommand::SelectTake {
+            track,
+            take: (state.active_take + 1) % state.take_count,
+        });
+    }
+
+    fn start_recorder(&mut self, overdub_track: Option<usize>, cx: &mut ViewContext<Self>) {
+        if let Err(e) = self.recorder.start() {
+            eprintln!("Error starting recording: {:?}", e);
+            return;
+        }
+        self.overdub_track = overdub_track;
+        self.watch_recorder(cx);
+    }
+
+    fn arm_recorder(&mut self, threshold: f32, cx: &mut ViewContext<Self>) {
+        if let Err(e) = self.recorder.arm(threshold) {
+            eprintln!("Error arming recording: {:?}", e);
+            return;
+        }
+        self.watch_recorder(cx);
+    }
+
+    // Ticks the metronome through the engine and starts the take on the beat after the last click.
+    fn start_count_in(&mut self, beats: u32, bpm: f64, cx: &mut ViewContext<Self>) {
+        let beat = Duration::from_secs_f64(60.0 / bpm);
+        self.count_in_beats_left = Some(beats);
+        self.count_in_task = Some(cx.spawn(|this, mut cx| async move {
+            for beat_index in 0..beats {
+                let counting = this.update(&mut cx, |root, cx| {
+                    let _s = root.engine_sender.send(EngineCommand::Click {
+                        accent: beat_index == 0,
+                    });
+                    root.count_in_beats_left = Some(beats - beat_index);
+                    cx.notify();
+                });
+                if counting.is_err() {
+                    return;
+                }
+                cx.background_executor().timer(beat).await;
+            }
+            let _s = this.update(&mut cx, |root, cx| {
+                root.count_in_beats_left = None;
+                root.start_recorder(None, cx);
+            });
+        }));
+        cx.notify();
+    }
+
+    fn watch_recorder(&mut self, cx: &mut ViewContext<Self>) {
+        // Keeps overdub input flowing to the engine and the recorder counters on screen.
+        self.recorder_task = Some(cx.spawn(|this, mut cx| async move {
+            loop {
+                cx.background_executor().timer(RECORDER_REFRESH).await;
+                let watching = this.update(&mut cx, |root, cx| {
+                    if let Some(track) = root.overdub_track {
+                        if let Some(recording) = root.recorder.take_recording() {
+                            root.send_overdub(track, recording);
+                        }
+                    }
+                    if let Some((track, punch_count)) = root.punch_track {
+                        if let Some(recording) = root.recorder.take_recording() {
+                            let _s = root.engine_sender.send(EngineCommand::Punch {
+                                track,
+                                frames: recording.frames(),
+                                sample_rate: recording.sample_rate,
+                            });
+                        }
+                        // The engine counts the punch once the playhead has left the punch region.
+                        let punched = root
+                            .engine_state_model
+                            .read(cx)
+                            .state
+                            .tracks
+                            .get(track)
+                            .is_some_and(|state| state.punch_count > punch_count);
+                        if punched {
+                            root.punch_track = None;
+                            root.recorder.stop();
+                        }
+                    }
+                    cx.notify();
+                    root.recorder.is_recording() || root.recorder.is_armed()
+                });
+                if !watching.unwrap_or(false) {
+                    break;
+                }
+            }
+        }));
+        cx.notify();
+    }
+
+    fn stop_recorder(&mut self) -> Option<Recording> {
+        self.recorder_task = None;
+        self.overdub_track = None;
+        self.punch_track = None;
+        self.recorder.stop()
+    }
+
+    fn capture(&mut self, mode: CaptureMode, cx: &mut ViewContext<Self>) {
+        match self.recorder.capture(mode) {
+            Some(recording) => self.load_recording(recording, cx),
+            None => eprintln!("Nothing to capture from the input"),
+        }
+    }
+
+    fn set_routing(&mut self, routing: InputRouting, cx: &mut ViewContext<Self>) {
+        if let Err(e) = self.recorder.set_routing(routing) {
+            eprintln!("Error reopening input: {:?}", e);
+        }
+        cx.notify();
+    }
+
+    fn cycle_channel_mode(&mut self, cx: &mut ViewContext<Self>) {
+        let mut routing = self.recorder.routing();
+        routing.mode = routing.mode.next();
+        self.set_routing(routing, cx);
+    }
+
+    fn cycle_input_channel(&mut self, cx: &mut ViewContext<Self>) {
+        let channel_count = match input_channel_count() {
+            Ok(count) => count as usize,
+            Err(e) => {
+                eprintln!("Error reading input channels: {:?}", e);
+                return;
+            }
+        };
+        let mut routing = self.recorder.routing();
+        let step = match routing.mode {
+            ChannelMode::Single => 1,
+            ChannelMode::Downmix | ChannelMode::Stereo => 2,
+        };
+        // Only pairs that fit on the device are stepped through.
+        let next_channel = routing.first_channel + step;
+        routing.first_channel = if next_channel + step <= channel_count {
+            next_channel
+        } else {
+            0
+        };
+        self.set_routing(routing, cx);
+    }
+
+    fn send_overdub(&self, track: usize, recording: Recording) {
+        let _s = self.engine_sender.send(EngineCommand::Overdub {
+            track,
+            frames: recording.frames(),
+            sample_rate: recording.sample_rate,
+        });
+    }
+
+    // Decodes a dropped file off the UI thread, then draws it and loops it on the track.
+    fn load_file(&mut self, index: usize, path: String, cx: &mut ViewContext<Self>) {
+        let progress = self.tracks[index]
+            .waveform_model
+            .update(cx, |waveform, cx| {
+                waveform.start_loading(SharedString::from(path.clone()), cx)
+            });
+        let decode_progress = progress.clone();
+        cx.spawn(|this, mut cx| async move {
+            let cache_path = path.clone();
+            let cached = cx
+                .background_executor()
+                .spawn(async move { cached_waveform(cache_path) })
+                .await;
+            // The waveform can be drawn right away, the engine still has to wait for the samples.
+            if let Some(summary) = cached.clone() {
+                let waveform_progress = progress.clone();
+                let _s = this.update(&mut cx, |root, cx| {
+                    root.tracks[index]
+                        .waveform_model
+                        .update(cx, |waveform, cx| {
+                            waveform.show_cached(&waveform_progress, summary, cx)
+                        });
+                });
+            }
+            // Decoded once for both the waveform and the engine.
+            let result = cx
+                .background_executor()
+                .spawn(async move { decode_waveform(path, &decode_progress, cached) })
+                .await;
+            let _s = this.update(&mut cx, |root, cx| {
+                let track = &root.tracks[index];
+                let Some(audio) = track.waveform_model.update(cx, |waveform, cx| {
+                    waveform.finish_loading(&progress, result, cx)
+                }) else {
+                    return;
+                };
+                track.loop_model.update(cx, |square, _cx| {
+                    square.set_sound_duration(audio.duration());
+                });
+                let _s = root.engine_sender.send(EngineCommand::LoadSound {
+                    track: index,
+                    sound: audio.to_sound(),
+                });
+            });
+        })
+        .detach();
+    }
+
+    fn load_recording(&mut self, recording: Recording, cx: &mut ViewContext<Self>) {
+        let window_width = window_width(cx);
+        let track = &self.tracks[self.selected_track];
+        track.waveform_model.update(cx, |waveform, cx| {
+            waveform.update_from_recording(&recording, cx);
+        });
+        let _s = self.engine_sender.send(EngineCommand::LoadSound {
+            track: self.selected_track,
+            sound: recording.to_sound(),
+        });
+        // The loop follows the take, so it comes back around exactly where recording stopped.
+        track.loop_model.update(cx, |square, cx| {
+            square.set_sound_duration(recording.duration());
+            square.fit_loop(cx, recording.duration(), window_width);
+        });
         cx.notify();
     }
+
+    fn render_track(
+        &self,
+        index: usize,
+        bin_count: usize,
+        window_width: f64,
+        cx: &mut ViewContext<Self>,
+    ) -> Div {
+        let track = &self.tracks[index];
+        let loop_model = track.loop_model.read(cx);
+        let square_x = loop_model.screen_x(loop_model.loop_position, window_width);
+        let square_width = loop_model
+            .screen_width(loop_model.square_width)
+            .max(1.0.into());
+        let loop_progress = self.engine_state_model.read(cx).loop_progress(index);
+        let track_state = self
+            .engine_state_model
+            .read(cx)
+            .state
+            .tracks
+            .get(index)
+            .copied()
+            .unwrap_or_default();
+        let waveform_error = track.waveform_model.read(cx).error.clone();
+        let loading_fraction = track.waveform_model.read(cx).loading_fraction();
+        let file_name = track.waveform_model.read(cx).file_name();
+
+        let lane_top = LANES_TOP + index as f32 * LANE_HEIGHT;
+        let lane_center = lane_top + LANE_HEIGHT / 2.0;
+        let square_top = lane_center - SQUARE_HEIGHT / 2.0;
+
+        let lanes = track.waveform_model.read(cx).lanes(
+            self.waveform_view,
+            loop_model.view.0,
+            loop_model.view.1,
+            bin_count,
+        );
+        // Ticks along the top of the lane, in seconds of the visible part of the sound.
+        let (visible_start, visible_end) = loop_model.visible_seconds();
+        let ruler: Vec<_> = match ruler_step(visible_end - visible_start, window_width) {
+            Some(step) => ((visible_start / step).ceil() as i64..=(visible_end / step) as i64)
+                .map(|tick| {
+                    let seconds = tick as f64 * step;
+                    overlay()
+                        .position(point(
+                            loop_model.seconds_x(seconds, window_width),
+                            px(lane_top),
+                        ))
+                        .child(
+                            div()
+                                .pl_1()
+                                .border_l_1()
+                                .border_color(hsla(RULER.0, RULER.1, RULER.2, RULER.3))
+                                .text_xs()
+                                .text_color(hsla(RULER.0, RULER.1, RULER.2, RULER.3))
+                                .child(ruler_label(seconds, step)),
+                        )
+                })
+                .collect(),
+            None => Vec::new(),
+        };
+
+        // Painted in one pass, so the bin count is only limited by the window width.
+        let waveform = overlay().position(point(px(0.0), px(lane_top))).child(
+            canvas(move |bounds, cx| paint_waveform(bounds, &lanes, cx))
+                .w(px(window_width as f32))
+                .h(px(LANE_HEIGHT)),
+        );
+
+        div()
+            // Lane view
+            .child(
+                overlay().position(point(px(0.0), px(lane_top))).child(
+                    div()
+                        .w(px(LANE_WIDTH))
+                        .h(px(LANE_HEIGHT))
+                        .when(index == self.selected_track, |this| {
+                            this.bg(hsla(
+                                LANE_SELECTED.0,
+                                LANE_SELECTED.1,
+                                LANE_SELECTED.2,
+                                LANE_SELECTED.3,
+                            ))
+                        })
+                        // Track selection
+                        .on_mouse_down(
+                            gpui::MouseButton::Left,
+                            cx.listener(move |this, _, cx| {
+                                this.selected_track = index;
+                                cx.notify();
+                            }),
+                        ),
+                ),
+            )
+            .child(waveform)
+            // Loading view
+            .children(loading_fraction.map(|fraction| {
+                overlay()
+                    .position(point(px(8.0), px(lane_top + LANE_HEIGHT - 20.0)))
+                    .child(
+                        div()
+                            .flex()
+                            .flex_col()
+                            .gap_1()
+                            .text_xs()
+                            .text_color(hsla(LOADING.0, LOADING.1, LOADING.2, LOADING.3))
+                            .child(format!("LOADING {} {:.0}%", file_name, fraction * 100.0))
+                            .child(
+                                div()
+                                    .w(px((LANE_WIDTH - 16.0) * fraction))
+                                    .h(px(2.0))
+                                    .bg(hsla(LOADING.0, LOADING.1, LOADING.2, LOADING.3)),
+                            ),
+                    )
+            }))
+            // Decode error view
+            .children(waveform_error.map(|error| {
+                overlay()
+                    .position(point(px(8.0), px(lane_top + LANE_HEIGHT - 20.0)))
+                    .child(
+                        div()
+                            .text_xs()
+                            .text_color(hsla(
+                                WAVEFORM_ERROR.0,
+                                WAVEFORM_ERROR.1,
+                                WAVEFORM_ERROR.2,
+                                WAVEFORM_ERROR.3,
+                            ))
+                            .child(error),
+                    )
+            }))
+            // Square view
+            .child(
+                overlay().position(point(square_x, px(square_top))).child(
+                    div()
+                        .w(square_width)
+                        .h(px(SQUARE_HEIGHT))
+                        .bg(hsla(
+                            SQUARE.0,
+                            {
+                                let hue = loop_model.square_height / px(360.0);
+                                hue.abs().max(0.00001)
+                            },
+                            SQUARE.2,
+                            SQUARE.3,
+                        ))
+                        .shadow(smallvec![BoxShadow {
+                            color: hsla(
+                                SQUARE_SH.0,
+                                {
+                                    let hue = loop_model.square_height / px(360.0);
+                                    hue.abs().max(0.00001)
+                                },
+                                SQUARE_SH.2,
+                                SQUARE_SH.3
+                            ),
+                            blur_radius: px(11.),
+                            offset: Point::default(),
+                            spread_radius: px(9.)
+                        }])
+                        .rounded_md(),
+                ),
+            )
+            // Punch region view
+            .when(
+                index == self.selected_track || track_state.punch_state != PunchState::Off,
+                |this| {
+                    this.child(
+                        overlay()
+                            .position(point(
+                                square_x + square_width * loop_model.punch.0,
+                                px(square_top),
+                            ))
+                            .child(
+                                div()
+                                    .w(square_width * (loop_model.punch.1 - loop_model.punch.0))
+                                    .h(px(SQUARE_HEIGHT))
+                                    .bg(hsla(
+                                        PUNCH_REGION.0,
+                                        PUNCH_REGION.1,
+                                        PUNCH_REGION.2,
+                                        PUNCH_REGION.3,
+                                    ))
+                                    .rounded_md(),
+                            ),
+                    )
+                },
+            )
+            // Ruler view
+            .children(ruler)
+            // Loop readout view
+            .when(track_state.sound_duration > 0.0, |this| {
+                this.child(
+                    overlay()
+                        .position(point(
+                            px(window_width as f32 - READOUT_WIDTH - 8.0),
+                            px(lane_top + 18.0),
+                        ))
+                        .child(
+                            div()
+                                .w(px(READOUT_WIDTH))
+                                .flex()
+                                .flex_col()
+                                .items_end()
+                                .text_xs()
+                                .text_color(hsla(
+                                    READOUT_TEXT.0,
+                                    READOUT_TEXT.1,
+                                    READOUT_TEXT.2,
+                                    READOUT_TEXT.3,
+                                ))
+                                .children(loop_readout(&track_state, self.tempo)),
+                        ),
+                )
+            })
+            // Take view
+            .when(track_state.take_count > 1, |this| {
+                this.child(
+                    overlay()
+                        .position(point(px(8.0), px(lane_top + 18.0)))
+                        .child(
+                            div()
+                                .text_xs()
+                                .text_color(hsla(
+                                    TAKE_TEXT.0,
+                                    TAKE_TEXT.1,
+                                    TAKE_TEXT.2,
+                                    TAKE_TEXT.3,
+                                ))
+                                .child(format!(
+                                    "TAKE {}/{}",
+                                    track_state.active_take + 1,
+                                    track_state.take_count
+                                ))
+                                // Take controls
+                                .on_mouse_down(
+                                    gpui::MouseButton::Left,
+                                    cx.listener(move |this, _, cx| this.cycle_take(index, cx)),
+                                ),
+                        ),
+                )
+            })
+            // Feedback view, as the engine applies it
+            .child(
+                overlay()
+                    .position(point(square_x, px(square_top + SQUARE_HEIGHT - 2.0)))
+                    .child(
+                        div()
+                            .w(square_width * track_state.overdub_feedback)
+                            .h(px(2.0))
+                            .bg(hsla(FEEDBACK.0, FEEDBACK.1, FEEDBACK.2, FEEDBACK.3)),
+                    ),
+            )
+            // Playhead view
+            .child(
+                overlay()
+                    .position(point(
+                        square_x + square_width * loop_progress,
+                        px(square_top),
+                    ))
+                    .child(
+                        div()
+                            .w(px(1.0))
+                            .h(px(SQUARE_HEIGHT))
+                            .bg(hsla(PLAYHEAD.0, PLAYHEAD.1, PLAYHEAD.2, PLAYHEAD.3)),
+                    ),
+            )
+    }
 }
 
 impl Render for Root {
@@ -179,99 +1351,40 @@ impl Render for Root {
             _ => (800.0, 800.0),
         };
 
-        let new_spacing = calculate_spacing(
-            window_width as f32,
-            MAX_NUMBER_OF_SAMPLES_SHOWN,
-            WAVEFORM_SAMPLES_PIXELS,
-        );
+        // One bin for every few pixels, however long the sound.
+        let bin_count = (window_width as f32 / WAVEFORM_BIN_PIXELS) as usize;
 
-        let loop_model = &self.loop_model.read(cx);
-        let waveform_model_samples = &self.waveform_model.read(cx).samples;
-
-        let waveform = div().children(
-            waveform_model_samples
-                .as_ref()
-                .iter()
-                .flat_map(|samples_vec| samples_vec.iter().enumerate())
-                .map(|(i, &v)| {
-                    let x_position = i as f32 * (5.0 + new_spacing);
-                    let waveform_box =
-                        |offset_x: f32,
-                         value_multiplier: f32,
-                         colors: (f32, f32, f32, f32),
-                         shadow_colors: (f32, f32, f32, f32),
-                         anchor_corner: AnchorCorner| {
-                            overlay()
-                                .position(point(px(x_position + offset_x), 390.0.into()))
-                                .anchor(anchor_corner)
-                                .child(
-                                    div()
-                                        .w_1()
-                                        .h(px(v * value_multiplier))
-                                        .bg(hsla(colors.0, colors.1, colors.2, colors.3))
-                                        .rounded_lg()
-                                        .shadow(smallvec![BoxShadow {
-                                            color: hsla(
-                                                shadow_colors.0,
-                                                shadow_colors.1,
-                                                shadow_colors.2,
-                                                shadow_colors.3
-                                            ),
-                                            blur_radius: px(2.),
-                                            offset: Point::default(),
-                                            spread_radius: px(2.)
-                                        }]),
-                                )
-                        };
-
-                    let upper_waveform = waveform_box(
-                        0.0,
-                        100.0,
-                        WAVEFORM_UPPER,
-                        WAVEFORM_UPPER_SH,
-                        AnchorCorner::BottomRight,
-                    );
-                    let lower_waveform = waveform_box(
-                        -4.0,
-                        100.0,
-                        WAVEFORM_LOWER,
-                        WAVEFORM_LOWER_SH,
-                        AnchorCorner::TopLeft,
-                    );
-
-                    (upper_waveform, lower_waveform)
-                })
-                .flat_map(|(upper_waveform, lower_waveform)| {
-                    std::iter::once(upper_waveform).chain(std::iter::once(lower_waveform))
-                }),
-        );
+        let lanes: Vec<Div> = (0..self.tracks.len())
+            .map(|index| self.render_track(index, bin_count, window_width, cx))
+            .collect();
 
         // Construct main view tree
         div()
             .flex()
             .flex_col()
             .items_center()
-            .on_drop(cx.listener(|this, path: &ExternalPaths, _cx| {
+            .on_drop(cx.listener(|this, path: &ExternalPaths, cx| {
                 let p = path.paths()[0]
                     .to_str()
                     .unwrap_or("../assets/audio/piano.wav")
                     .to_owned();
-                let sound = Sound::from_path(p.clone()).unwrap();
-                let shared_path = SharedString::from(p);
-                this.waveform_model.update(_cx, |a, cx| {
-                    a.update_samples(shared_path.clone(), cx);
-                });
-                let _s = this.sound_sender.send(sound).unwrap();
+                let index = this.selected_track;
+                this.load_file(index, p, cx);
             }))
             .size_full()
             .bg(hsla(BG.0, BG.1, BG.2, BG.3))
-            .child(waveform)
+            .children(lanes)
             // Square controls
             .on_scroll_wheel(
                 cx.listener(move |this, s: &ScrollWheelEvent, cx| match s.delta {
                     ScrollDelta::Pixels(p) => {
-                        this.loop_model
+                        this.tracks[this.selected_track]
+                            .loop_model
                             .update(cx, |square, cx| match s.modifiers.control {
+                                true if s.modifiers.alt => {
+                                    square.zoom(cx, p.y, s.position.x, window_width.into());
+                                    square.scroll_view(cx, p.x, window_width.into());
+                                }
                                 true => square.change_loop(
                                     cx,
                                     Some(0.0001.into()),
@@ -279,6 +1392,10 @@ impl Render for Root {
                                     p.y,
                                     window_width.into(),
                                 ),
+                                false if s.modifiers.alt => square.change_feedback(cx, p.y),
+                                false if s.modifiers.shift => {
+                                    square.change_punch(cx, p.x, p.y, window_width.into())
+                                }
                                 false => match s.modifiers.command {
                                     true => square.change_loop(
                                         cx,
@@ -300,40 +1417,6 @@ impl Render for Root {
                     ScrollDelta::Lines(_) => {}
                 }),
             )
-            // Square view
-            .child(
-                overlay()
-                    .position(point(loop_model.loop_position, 325.0.into()))
-                    .child(
-                        div()
-                            .w(loop_model.square_width.max(1.0.into()))
-                            .h_32()
-                            .bg(hsla(
-                                SQUARE.0,
-                                {
-                                    let hue = loop_model.square_height / px(360.0);
-                                    hue.abs().max(0.00001)
-                                },
-                                SQUARE.2,
-                                SQUARE.3,
-                            ))
-                            .shadow(smallvec![BoxShadow {
-                                color: hsla(
-                                    SQUARE_SH.0,
-                                    {
-                                        let hue = loop_model.square_height / px(360.0);
-                                        hue.abs().max(0.00001)
-                                    },
-                                    SQUARE_SH.2,
-                                    SQUARE_SH.3
-                                ),
-                                blur_radius: px(11.),
-                                offset: Point::default(),
-                                spread_radius: px(9.)
-                            }])
-                            .rounded_md(),
-                    ),
-            )
             // Help view
             .child(div().w(px(165.0)).when(
                 self.help_model.read(cx).is_shown,
@@ -373,6 +1456,462 @@ impl Render for Root {
                             ),
                     ),
             )
+            // Record button
+            .child(
+                overlay()
+                    .position(point(55.0.into(), (window_height - 16.0).into()))
+                    .child(
+                        div()
+                            .size_6()
+                            .rounded_full()
+                            .flex()
+                            .justify_center()
+                            .items_center()
+                            .text_xs()
+                            .bg(
+                                match self.recorder.is_recording()
+                                    && self.overdub_track.is_none()
+                                    && self.punch_track.is_none()
+                                {
+                                    true => hsla(
+                                        RECORD_IC_ACTIVE.0,
+                                        RECORD_IC_ACTIVE.1,
+                                        RECORD_IC_ACTIVE.2,
+                                        RECORD_IC_ACTIVE.3,
+                                    ),
+                                    false
+                                        if self.recorder.is_armed()
+                                            || self.count_in_beats_left.is_some() =>
+                                    {
+                                        hsla(
+                                            RECORD_IC_ARMED.0,
+                                            RECORD_IC_ARMED.1,
+                                            RECORD_IC_ARMED.2,
+                                            RECORD_IC_ARMED.3,
+                                        )
+                                    }
+                                    false => {
+                                        hsla(RECORD_IC.0, RECORD_IC.1, RECORD_IC.2, RECORD_IC.3)
+                                    }
+                                },
+                            )
+                            .children(self.count_in_beats_left.map(|beats| beats.to_string()))
+                            // Record controls
+                            .on_mouse_down(
+                                gpui::MouseButton::Left,
+                                cx.listener(|this, _, cx| this.toggle_recording(cx)),
+                            ),
+                    ),
+            )
+            // Overdub button
+            .child(
+                overlay()
+                    .position(point(90.0.into(), (window_height - 16.0).into()))
+                    .child(
+                        div()
+                            .size_6()
+                            .rounded_full()
+                            .bg(match self.overdub_track.is_some() {
+                                true => hsla(
+                                    OVERDUB_IC_ACTIVE.0,
+                                    OVERDUB_IC_ACTIVE.1,
+                                    OVERDUB_IC_ACTIVE.2,
+                                    OVERDUB_IC_ACTIVE.3,
+                                ),
+                                false => {
+                                    hsla(OVERDUB_IC.0, OVERDUB_IC.1, OVERDUB_IC.2, OVERDUB_IC.3)
+                                }
+                            })
+                            // Overdub controls
+                            .on_mouse_down(
+                                gpui::MouseButton::Left,
+                                cx.listener(|this, _, cx| this.toggle_overdub(cx)),
+                            ),
+                    ),
+            )
+            // Capture button
+            .child(
+                overlay()
+                    .position(point(125.0.into(), (window_height - 16.0).into()))
+                    .child(
+                        div()
+                            .size_6()
+                            .rounded_full()
+                            .bg(hsla(CAPTURE_IC.0, CAPTURE_IC.1, CAPTURE_IC.2, CAPTURE_IC.3))
+                            // Capture controls
+                            .on_mouse_down(
+                                gpui::MouseButton::Left,
+                                cx.listener(|this, event: &MouseDownEvent, cx| {
+                                    let mode = match event.modifiers.control {
+                                        true => CaptureMode::LastSeconds(DEFAULT_CAPTURE_SECONDS),
+                                        false => CaptureMode::LastPhrase {
+                                            threshold: DEFAULT_PHRASE_THRESHOLD,
+                                        },
+                                    };
+                                    this.capture(mode, cx);
+                                }),
+                            ),
+                    ),
+            )
+            // Punch button
+            .child(
+                overlay()
+                    .position(point(160.0.into(), (window_height - 16.0).into()))
+                    .child(
+                        div()
+                            .size_6()
+                            .rounded_full()
+                            .bg(match self.punch_track.is_some() {
+                                true => hsla(
+                                    PUNCH_IC_ACTIVE.0,
+                                    PUNCH_IC_ACTIVE.1,
+                                    PUNCH_IC_ACTIVE.2,
+                                    PUNCH_IC_ACTIVE.3,
+                                ),
+                                false => hsla(PUNCH_IC.0, PUNCH_IC.1, PUNCH_IC.2, PUNCH_IC.3),
+                            })
+                            // Punch controls
+                            .on_mouse_down(
+                                gpui::MouseButton::Left,
+                                cx.listener(|this, _, cx| this.toggle_punch(cx)),
+                            ),
+                    ),
+            )
+            // Resample button
+            .child(
+                overlay()
+                    .position(point(195.0.into(), (window_height - 16.0).into()))
+                    .child(
+                        div()
+                            .size_6()
+                            .rounded_full()
+                            .bg(match self.resampling {
+                                true => hsla(
+                                    RESAMPLE_IC_ACTIVE.0,
+                                    RESAMPLE_IC_ACTIVE.1,
+                                    RESAMPLE_IC_ACTIVE.2,
+                                    RESAMPLE_IC_ACTIVE.3,
+                                ),
+                                false => {
+                                    hsla(RESAMPLE_IC.0, RESAMPLE_IC.1, RESAMPLE_IC.2, RESAMPLE_IC.3)
+                                }
+                            })
+                            // Resample controls
+                            .on_mouse_down(
+                                gpui::MouseButton::Left,
+                                cx.listener(|this, event: &MouseDownEvent, cx| {
+                                    this.toggle_resample(event.modifiers.control, cx)
+                                }),
+                            ),
+                    ),
+            )
+            // Transport button
+            .child(
+                overlay()
+                    .position(point(230.0.into(), (window_height - 16.0).into()))
+                    .child(
+                        div()
+                            .size_6()
+                            .rounded_full()
+                            .bg(match self.is_playing(cx) {
+                                true => hsla(
+                                    TRANSPORT_IC.0,
+                                    TRANSPORT_IC.1,
+                                    TRANSPORT_IC.2,
+                                    TRANSPORT_IC.3,
+                                ),
+                                false => hsla(
+                                    TRANSPORT_IC_PAUSED.0,
+                                    TRANSPORT_IC_PAUSED.1,
+                                    TRANSPORT_IC_PAUSED.2,
+                                    TRANSPORT_IC_PAUSED.3,
+                                ),
+                            })
+                            // Transport controls
+                            .on_mouse_down(
+                                gpui::MouseButton::Left,
+                                cx.listener(|this, _, cx| this.toggle_pause(cx)),
+                            ),
+                    ),
+            )
+            // Input routing
+            .child(
+                overlay()
+                    .position(point(265.0.into(), (window_height - 14.0).into()))
+                    .child(
+                        div()
+                            .flex()
+                            .gap_3()
+                            .text_xs()
+                            .text_color(hsla(
+                                ROUTING_TEXT.0,
+                                ROUTING_TEXT.1,
+                                ROUTING_TEXT.2,
+                                ROUTING_TEXT.3,
+                            ))
+                            .child(
+                                div()
+                                    .child(self.recorder.routing().to_string())
+                                    .on_mouse_down(
+                                        gpui::MouseButton::Left,
+                                        cx.listener(|this, _, cx| this.cycle_input_channel(cx)),
+                                    ),
+                            )
+                            .child(
+                                div()
+                                    .child(self.recorder.routing().mode.to_string())
+                                    .on_mouse_down(
+                                        gpui::MouseButton::Left,
+                                        cx.listener(|this, _, cx| this.cycle_channel_mode(cx)),
+                                    ),
+                            )
+                            .child(div().child(self.record_trigger.to_string()).on_mouse_down(
+                                gpui::MouseButton::Left,
+                                cx.listener(|this, event: &MouseDownEvent, cx| {
+                                    this.record_trigger = match event.modifiers.control {
+                                        true => this.record_trigger.adjust(),
+                                        false => this.record_trigger.next(),
+                                    };
+                                    cx.notify();
+                                }),
+                            )),
+                    ),
+            )
+            // Tempo view
+            .child(
+                overlay()
+                    .position(point(
+                        (window_width - 160.0).into(),
+                        (window_height - 14.0).into(),
+                    ))
+                    .child(
+                        div()
+                            .text_xs()
+                            .text_color(hsla(
+                                ROUTING_TEXT.0,
+                                ROUTING_TEXT.1,
+                                ROUTING_TEXT.2,
+                                ROUTING_TEXT.3,
+                            ))
+                            .child(match self.tempo {
+                                Some(bpm) => format!("{:.0} BPM", bpm),
+                                None => "NO BPM".to_string(),
+                            })
+                            // Tempo controls
+                            .on_mouse_down(
+                                gpui::MouseButton::Left,
+                                cx.listener(|this, _, cx| {
+                                    this.tempo = next_tempo(this.tempo);
+                                    cx.notify();
+                                }),
+                            ),
+                    ),
+            )
+            // Waveform view
+            .child(
+                overlay()
+                    .position(point(
+                        (window_width - 90.0).into(),
+                        (window_height - 14.0).into(),
+                    ))
+                    .child(
+                        div()
+                            .text_xs()
+                            .text_color(hsla(
+                                ROUTING_TEXT.0,
+                                ROUTING_TEXT.1,
+                                ROUTING_TEXT.2,
+                                ROUTING_TEXT.3,
+                            ))
+                            .child(self.waveform_view.to_string())
+                            // Waveform view controls
+                            .on_mouse_down(
+                                gpui::MouseButton::Left,
+                                cx.listener(|this, _, cx| {
+                                    this.waveform_view = this.waveform_view.next();
+                                    cx.notify();
+                                }),
+                            ),
+                    ),
+            )
+            // Recorder warnings
+            .when(
+                self.recorder.dropped_samples() > 0 || self.recorder.clipped_samples() > 0,
+                |this| {
+                    let mut warnings = Vec::new();
+                    if self.recorder.dropped_samples() > 0 {
+                        warnings.push(format!(
+                            "DROPPED {} SAMPLES IN {} OVERRUNS",
+                            self.recorder.dropped_samples(),
+                            self.recorder.overruns()
+                        ));
+                    }
+                    if self.recorder.clipped_samples() > 0 {
+                        warnings.push(format!(
+                            "CLIPPED {} SAMPLES",
+                            self.recorder.clipped_samples()
+                        ));
+                    }
+                    this.child(
+                        overlay()
+                            .position(point(485.0.into(), (window_height - 14.0).into()))
+                            .child(
+                                div()
+                                    .text_xs()
+                                    .text_color(hsla(
+                                        RECORDER_WARNING.0,
+                                        RECORDER_WARNING.1,
+                                        RECORDER_WARNING.2,
+                                        RECORDER_WARNING.3,
+                                    ))
+                                    .child(warnings.join("  ")),
+                            ),
+                    )
+                },
+            )
+    }
+}
+
+fn resample_path() -> PathBuf {
+    let seconds = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or_default();
+    PathBuf::from(RESAMPLE_DIR).join(format!("resample-{}.wav", seconds))
+}
+
+// Spacing of the ruler ticks over `visible_seconds`, None when nothing is loaded.
+fn ruler_step(visible_seconds: f64, window_width: f64) -> Option<f64> {
+    if visible_seconds <= 0.0 {
+        return None;
+    }
+    let pixels_per_second = window_width / visible_seconds;
+    Some(
+        RULER_STEPS
+            .iter()
+            .copied()
+            .find(|step| step * pixels_per_second >= RULER_MIN_TICK_PIXELS)
+            .unwrap_or(RULER_STEPS[RULER_STEPS.len() - 1]),
+    )
+}
+
+// Seconds with as many decimals as the ticks are apart.
+fn ruler_label(seconds: f64, step: f64) -> String {
+    let decimals = (-step.log10()).ceil().max(0.0) as usize;
+    format!("{:.*}s", decimals, seconds)
+}
+
+// Loop bounds as the engine applied them, in seconds, frames and, with a tempo, bars and beats.
+fn loop_readout(state: &TrackState, tempo: Option<f64>) -> Vec<String> {
+    let (start_frame, end_frame) = state.loop_frames();
+    let mut lines = vec![
+        format!(
+            "{:.3}s - {:.3}s  LEN {:.3}s",
+            state.loop_start,
+            state.loop_end,
+            state.loop_end - state.loop_start
+        ),
+        format!(
+            "{} - {} SMP  LEN {}",
+            start_frame,
+            end_frame,
+            end_frame - start_frame
+        ),
+    ];
+    if let Some(bpm) = tempo {
+        lines.push(format!(
+            "{} - {}  LEN {:.2} BEATS",
+            bars_beats(state.loop_start, bpm),
+            bars_beats(state.loop_end, bpm),
+            (state.loop_end - state.loop_start) * bpm / 60.0
+        ));
+    }
+    lines
+}
+
+// Position as bar:beat counting from 1:1, the beat keeping its fraction.
+fn bars_beats(seconds: f64, bpm: f64) -> String {
+    // Rounded to the shown hundredths before splitting, so a beat never reads as 5.00.
+    let beats = (seconds * bpm / 60.0 * 100.0).round() / 100.0;
+    let bar = (beats / BEATS_PER_BAR).floor();
+    format!(
+        "{}:{:.2}",
+        bar as u64 + 1,
+        beats - bar * BEATS_PER_BAR + 1.0
+    )
+}
+
+// Steps through TEMPOS and back to no tempo.
+fn next_tempo(tempo: Option<f64>) -> Option<f64> {
+    match tempo {
+        None => Some(TEMPOS[0]),
+        Some(bpm) => TEMPOS.iter().copied().find(|&next| next > bpm),
+    }
+}
+
+// Draws each lane's peak envelope, min to max, with the RMS solid inside it.
+fn paint_waveform(bounds: &Bounds<Pixels>, lanes: &[Vec<PeakBin>], cx: &mut WindowContext) {
+    // Each channel, or mid and side, gets an equal slice of the lane.
+    let sub_lane_height = bounds.size.height.0 / lanes.len().max(1) as f32;
+    // Pixels from the center to full scale.
+    let amplitude = WAVEFORM_AMPLITUDE / lanes.len().max(1) as f32;
+    for (lane, bins) in lanes.iter().enumerate() {
+        let center = bounds.origin.y.0 + sub_lane_height * (lane as f32 + 0.5);
+        let bands: [(fn(&PeakBin) -> (f32, f32), _, _); 2] = [
+            (
+                |bin| (bin.max.max(0.0), bin.min.min(0.0)),
+                WAVEFORM_LOWER,
+                WAVEFORM_LOWER_SH,
+            ),
+            (|bin| (bin.rms, -bin.rms), WAVEFORM_UPPER, WAVEFORM_UPPER_SH),
+        ];
+        for (band, color, shadow_color) in bands {
+            for (spread, (h, s, l, a)) in [(WAVEFORM_SHADOW_SPREAD, shadow_color), (0.0, color)] {
+                if let Some(path) = band_path(bounds, center, amplitude, spread, bins, band) {
+                    cx.paint_path(path, hsla(h, s, l, a));
+                }
+            }
+        }
+    }
+}
+
+// The outline of `band`'s top and bottom over every bin, widened by `spread` on both sides.
+fn band_path(
+    bounds: &Bounds<Pixels>,
+    center: f32,
+    amplitude: f32,
+    spread: f32,
+    bins: &[PeakBin],
+    band: fn(&PeakBin) -> (f32, f32),
+) -> Option<gpui::Path<Pixels>> {
+    let bin_width = bounds.size.width.0 / bins.len().max(1) as f32;
+    let x = |i: usize| bounds.origin.x.0 + (i as f32 + 0.5) * bin_width;
+    let edges: Vec<(f32, f32)> = bins
+        .iter()
+        .map(|bin| {
+            let (top, bottom) = band(bin);
+            let top = center - top * amplitude - spread;
+            // Silence still shows as a line.
+            let bottom = (center - bottom * amplitude + spread).max(top + 1.0);
+            (top, bottom)
+        })
+        .collect();
+    let (first_top, _) = *edges.first()?;
+    let mut path = gpui::Path::new(point(px(x(0)), px(first_top)));
+    for (i, &(top, _)) in edges.iter().enumerate().skip(1) {
+        path.line_to(point(px(x(i)), px(top)));
+    }
+    for (i, &(_, bottom)) in edges.iter().enumerate().rev() {
+        path.line_to(point(px(x(i)), px(bottom)));
+    }
+    path.line_to(point(px(x(0)), px(first_top)));
+    Some(path)
+}
+
+fn window_width(cx: &mut ViewContext<Root>) -> GlobalPixels {
+    match cx.window_bounds() {
+        WindowBounds::Fixed(bounds) => bounds.size.width,
+        _ => 800.0.into(),
     }
 }
 
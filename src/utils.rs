@@ -1,3 +1,5 @@
+use kittyaudio::Frame;
+
 pub fn scale_value(input: f64, input_range: (f64, f64), output_range: (f64, f64)) -> f64 {
     let (input_min, input_max) = input_range;
     let (output_min, output_max) = output_range;
@@ -9,27 +11,43 @@ pub fn scale_value(input: f64, input_range: (f64, f64), output_range: (f64, f64)
     output_min + proportion * (output_max - output_min)
 }
 
-pub fn calculate_spacing(new_width: f32, num_samples: i32, samples_width: f32) -> f32 {
-    let total_width = num_samples as f32 * samples_width;
-    let num_gaps = (num_samples - 1) as f32;
-    if num_gaps > 0.0 {
-        (new_width - total_width) / num_gaps
-    } else {
-        0.0
+pub fn resample_frames(frames: &[Frame], from_rate: f64, to_rate: f64) -> Vec<Frame> {
+    if frames.is_empty() || from_rate <= 0.0 || to_rate <= 0.0 || from_rate == to_rate {
+        return frames.to_vec();
     }
+    let ratio = from_rate / to_rate;
+    let target_length = (frames.len() as f64 / ratio).round() as usize;
+    (0..target_length)
+        .map(|i| {
+            let position = i as f64 * ratio;
+            let index = position as usize;
+            let fraction = (position - index as f64) as f32;
+            let current = frames[index.min(frames.len() - 1)];
+            let next = frames[(index + 1).min(frames.len() - 1)];
+            Frame {
+                left: current.left + (next.left - current.left) * fraction,
+                right: current.right + (next.right - current.right) * fraction,
+            }
+        })
+        .collect()
 }
 
-pub fn scale_values_to_unit_range(values: Vec<f32>) -> Vec<f32> {
-    let (min_val, max_val) = values.iter().cloned().fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), val| {
-        (min.min(val), max.max(val))
-    });
-
-    if min_val >= max_val {
-        return Vec::new();
+// Interleaved mono or stereo samples as kittyaudio frames, mono going to both sides.
+pub fn interleaved_to_frames(samples: &[f32], channels: u16) -> Vec<Frame> {
+    match channels {
+        1 => samples
+            .iter()
+            .map(|&mono| Frame {
+                left: mono,
+                right: mono,
+            })
+            .collect(),
+        _ => samples
+            .chunks_exact(channels.max(2) as usize)
+            .map(|frame| Frame {
+                left: frame[0],
+                right: frame[1],
+            })
+            .collect(),
     }
-
-    values
-        .into_iter()
-        .map(|x| (x - min_val) / (max_val - min_val))
-        .collect()
 }